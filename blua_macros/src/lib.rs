@@ -0,0 +1,66 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Attribute, Ident, ImplItem, ItemImpl};
+
+/// Put on an impl block to generate `register_lua_methods(app)` for it, which registers
+/// every method with `register_object_function` and every associated function with
+/// `register_non_self_object_function`. Generic functions and ones marked `#[blua(skip)]`
+/// are left out.
+///
+/// ```ignore
+/// #[blua::methods]
+/// impl Stretch {
+///     pub fn get_sum(&self) -> f32 {
+///         self.x + self.y
+///     }
+/// }
+///
+/// Stretch::register_lua_methods(&mut app);
+/// ```
+#[proc_macro_attribute]
+pub fn methods(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut item_impl = parse_macro_input!(item as ItemImpl);
+
+    let mut registrations = vec![];
+    for impl_item in &mut item_impl.items {
+        let ImplItem::Fn(function) = impl_item else {
+            continue;
+        };
+        let skip = function.attrs.iter().any(is_skip);
+        function.attrs.retain(|attr| !is_skip(attr));
+        if skip || !function.sig.generics.params.is_empty() {
+            continue;
+        }
+        let ident = &function.sig.ident;
+        let name = ident.to_string();
+        let register = if function.sig.receiver().is_some() {
+            quote!(register_object_function)
+        } else {
+            quote!(register_non_self_object_function)
+        };
+        registrations.push(quote! {
+            ::blua::AppExtensionFunctionRegisterTrait::#register::<Self>(
+                app,
+                ::bevy::reflect::func::IntoFunction::into_function(Self::#ident).with_name(#name),
+            );
+        });
+    }
+
+    let self_ty = &item_impl.self_ty;
+    let (impl_generics, _, where_clause) = item_impl.generics.split_for_impl();
+    quote! {
+        #item_impl
+
+        impl #impl_generics #self_ty #where_clause {
+            /// Registers the functions of the `#[blua::methods]` impl block for lua.
+            pub fn register_lua_methods(app: &mut ::bevy::app::App) {
+                #(#registrations)*
+            }
+        }
+    }
+    .into()
+}
+
+fn is_skip(attr: &Attribute) -> bool {
+    attr.path().is_ident("blua") && attr.parse_args::<Ident>().is_ok_and(|ident| ident == "skip")
+}
@@ -19,9 +19,6 @@ fn main() {
     .add_plugins(LuaPlugin);
     app.register_type::<CubeMarker>();
     app.register_object_function::<Vec3>(add.into_function().with_name("add"));
-    app.register_non_self_object_function::<Transform>(
-        default_uwu.into_function().with_name("default"),
-    );
     app.register_type::<Transform>();
     app.add_systems(Startup, setup);
     app.run();
@@ -31,10 +28,6 @@ fn add(a: &Vec3, b: &Vec3) -> Vec3 {
     a + b
 }
 
-fn default_uwu() -> Transform {
-    Transform::default()
-}
-
 #[derive(Component, Reflect)]
 #[reflect(Component)]
 pub struct CubeMarker {
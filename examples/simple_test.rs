@@ -16,12 +16,7 @@ fn main() {
     app.add_systems(Startup, setup);
     app.register_type::<Stretch>();
     app.world_mut().register_component::<Stretch>();
-    app.register_object_function::<Stretch>(Stretch::get_sum.into_function().with_name("get_sum"));
-    app.register_object_function::<Stretch>(
-        Stretch::get_sum_with
-            .into_function()
-            .with_name("get_sum_with"),
-    );
+    Stretch::register_lua_methods(&mut app);
     app.register_object_function::<Time<()>>(
         Time::<()>::elapsed_secs
             .into_function()
@@ -37,6 +32,7 @@ pub struct Stretch {
     pub y: f32,
 }
 
+#[blua::methods]
 impl Stretch {
     pub fn get_sum(&self) -> f32 {
         self.x + self.y
@@ -0,0 +1,480 @@
+use crate::construct_stuff::{lua_to_reflect, number_to_reflect, type_registry};
+use crate::reflect_stuff::{LuaEntity, PtrState, ReflectPtr, ReflectType};
+use crate::{LuaCallback, TableReflectWrapper};
+use anyhow::{anyhow, bail};
+use bevy::prelude::*;
+use bevy::reflect::func::args::{ArgInfo, Ownership};
+use bevy::reflect::func::{ArgList, FunctionInfo};
+use bevy::reflect::{
+    DynamicEnum, DynamicTuple, DynamicVariant, ReflectFromReflect, TypeInfo, TypeRegistry,
+    VariantInfo,
+};
+use piccolo::{Context, Value};
+use std::any::TypeId;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// A lua value converted for one parameter of a rust function.
+pub enum LuaArg<'gc> {
+    /// Borrowed straight out of the `ReflectPtr` the lua value points at.
+    Ptr(&'gc ReflectPtr),
+    /// Converted into a new value, taken when the parameter is by value.
+    Value(Option<Box<dyn PartialReflect>>),
+    /// A boxed value that can't be cloned, moved out of lua by `take_moved` once the overload
+    /// is picked.
+    Move(&'gc ReflectPtr),
+}
+
+impl<'gc> LuaArg<'gc> {
+    pub fn take_moved(&mut self) -> anyhow::Result<()> {
+        if let LuaArg::Move(reflect) = self {
+            *self = LuaArg::Value(Some(reflect.take()?));
+        }
+        Ok(())
+    }
+
+    pub fn push<'a>(
+        &'a mut self,
+        args_list: ArgList<'a>,
+        ownership: Ownership,
+    ) -> anyhow::Result<ArgList<'a>> {
+        Ok(match (self, ownership) {
+            (LuaArg::Ptr(reflect), Ownership::Mut) => {
+                args_list.push_mut(reflect.try_field_value_mut()?.as_partial_reflect_mut())
+            }
+            (LuaArg::Ptr(reflect), _) => {
+                args_list.push_ref(reflect.try_field_value_ref()?.as_partial_reflect())
+            }
+            (LuaArg::Value(value), Ownership::Owned) => args_list.push_boxed(value.take().unwrap()),
+            (LuaArg::Value(value), Ownership::Ref) => args_list.push_ref(value.as_deref().unwrap()),
+            (LuaArg::Value(value), Ownership::Mut) => {
+                args_list.push_mut(value.as_deref_mut().unwrap())
+            }
+            (LuaArg::Move(_), _) => bail!("the value wasn't moved out of lua"),
+        })
+    }
+}
+
+/// The `ptr_state` a reference the function returns can share, if every reference argument
+/// points into a `ReflectPtr` with that same state. `None` when it could borrow from a
+/// converted argument, which is dropped once the call returns, or from a boxed value, which
+/// lua can collect or move out while its state stays valid.
+pub fn borrowed_ptr_state(args: &[LuaArg], info: &FunctionInfo) -> Option<Rc<RefCell<PtrState>>> {
+    let mut ptr_state: Option<Rc<RefCell<PtrState>>> = None;
+    for (arg, arg_info) in args.iter().zip(info.args()) {
+        if matches!(arg_info.ownership(), Ownership::Owned) {
+            continue;
+        }
+        let LuaArg::Ptr(reflect) = arg else {
+            return None;
+        };
+        if matches!(reflect.data, ReflectType::Boxed(_)) {
+            return None;
+        }
+        let state = reflect.ptr_state();
+        match &ptr_state {
+            Some(existing) if !Rc::ptr_eq(existing, &state) => return None,
+            _ => ptr_state = Some(state),
+        }
+    }
+    ptr_state
+}
+
+/// Converts every lua argument to the type of its parameter, erroring with the signature of
+/// the function when one doesn't fit or the argument count is wrong. Nothing is moved out of
+/// lua, so a failed overload leaves the arguments as they were. Trailing `Option` parameters
+/// can be left out and are `None`.
+pub fn lua_to_args<'gc>(
+    ctx: Context<'gc>,
+    mut values: Vec<Value<'gc>>,
+    info: &FunctionInfo,
+) -> anyhow::Result<Vec<LuaArg<'gc>>> {
+    let optional = info
+        .args()
+        .iter()
+        .rev()
+        .take_while(|arg| {
+            matches!(arg.ownership(), Ownership::Owned)
+                && arg.type_path().starts_with("core::option::Option<")
+        })
+        .count();
+    let required = info.arg_count() - optional;
+    if values.len() < required || values.len() > info.arg_count() {
+        let expected = if optional == 0 {
+            info.arg_count().to_string()
+        } else {
+            format!("{required} to {}", info.arg_count())
+        };
+        bail!(
+            "{} takes {expected} arguments, got {}",
+            signature(info),
+            values.len()
+        );
+    }
+    values.resize(info.arg_count(), Value::Nil);
+    values
+        .into_iter()
+        .zip(info.args())
+        .map(|(value, arg_info)| {
+            lua_to_arg(ctx, value, arg_info).map_err(|err| {
+                anyhow!(
+                    "{}: argument {} {err}",
+                    signature(info),
+                    arg_info.index() + 1
+                )
+            })
+        })
+        .collect()
+}
+
+fn lua_to_arg<'gc>(
+    ctx: Context<'gc>,
+    value: Value<'gc>,
+    arg_info: &ArgInfo,
+) -> anyhow::Result<LuaArg<'gc>> {
+    if let Value::UserData(user_data) = value {
+        if let Ok(reflect) = user_data.downcast_static::<ReflectPtr>() {
+            let actual = reflect.try_field_value_ref()?.reflect_type_info();
+            if actual.type_id() != arg_info.type_id() {
+                bail!(
+                    "expected a {}, got a {}",
+                    arg_info.type_path(),
+                    actual.type_path()
+                );
+            }
+            if matches!(arg_info.ownership(), Ownership::Mut) && !reflect.is_mutable() {
+                bail!("is read only, like a `.ref` query term or `Res`, and can't be `&mut`");
+            }
+            return Ok(match arg_info.ownership() {
+                Ownership::Owned => match reflect.owned_clone(ctx)? {
+                    Some(value) => LuaArg::Value(Some(value)),
+                    None => LuaArg::Move(reflect),
+                },
+                Ownership::Ref | Ownership::Mut => LuaArg::Ptr(reflect),
+            });
+        }
+    }
+    let value = lua_to_owned(ctx, value, arg_info.type_id(), arg_info.type_path())?;
+    Ok(LuaArg::Value(Some(value)))
+}
+
+/// Numbers are converted to any int or float width as long as they fit, strings to `String`,
+/// `Cow<str>`, `Name` or unit enum variants, `nil` to `Option::None` and tables to structs.
+pub fn lua_to_owned<'gc>(
+    ctx: Context<'gc>,
+    value: Value<'gc>,
+    type_id: TypeId,
+    type_path: &str,
+) -> anyhow::Result<Box<dyn PartialReflect>> {
+    match type_registry(ctx) {
+        Some(registry) => lua_to_owned_in(ctx, value, type_id, type_path, &registry.read()),
+        None => lua_to_owned_in(ctx, value, type_id, type_path, &TypeRegistry::empty()),
+    }
+}
+
+/// `lua_to_owned` with the registry already locked, for converting the fields of a value
+/// that's being built.
+pub fn lua_to_owned_in<'gc>(
+    ctx: Context<'gc>,
+    value: Value<'gc>,
+    type_id: TypeId,
+    type_path: &str,
+    registry: &TypeRegistry,
+) -> anyhow::Result<Box<dyn PartialReflect>> {
+    match value {
+        Value::Table(table) if type_id == TypeId::of::<TableReflectWrapper>() => {
+            return Ok(Box::new(unsafe { TableReflectWrapper::new(table) }));
+        }
+        Value::Function(function) if type_id == TypeId::of::<LuaCallback>() => {
+            return Ok(Box::new(LuaCallback::new(ctx.stash(function))));
+        }
+        Value::Nil if type_id == TypeId::of::<()>() => return Ok(Box::new(())),
+        _ => {}
+    }
+    if type_path.starts_with("core::option::Option<") {
+        return option_to_reflect(ctx, value, type_id, type_path, registry);
+    }
+    if let Some(number) = number_to_reflect(value, type_id)? {
+        return Ok(number);
+    }
+    match value {
+        Value::Boolean(b) if type_id == TypeId::of::<bool>() => return Ok(Box::new(b)),
+        Value::String(s) if type_id == TypeId::of::<String>() => {
+            return Ok(Box::new(s.to_str()?.to_string()));
+        }
+        Value::String(s) if type_id == TypeId::of::<Cow<'static, str>>() => {
+            return Ok(Box::new(Cow::<'static, str>::Owned(
+                s.to_str()?.to_string(),
+            )));
+        }
+        Value::String(s) if type_id == TypeId::of::<Name>() => {
+            return Ok(Box::new(Name::new(s.to_str()?.to_string())));
+        }
+        Value::UserData(user_data) if type_id == TypeId::of::<Entity>() => {
+            if let Ok(entity) = user_data.downcast_static::<LuaEntity>() {
+                return Ok(Box::new(entity.0));
+            }
+        }
+        _ => {}
+    }
+    // anything else the type registry knows how to build, like structs from tables
+    if let Some(type_info) = registry.get_type_info(type_id) {
+        return lua_to_reflect(ctx, value, type_info, registry);
+    }
+    bail!("expected a {type_path}, got a lua {}", value.type_name())
+}
+
+fn option_to_reflect<'gc>(
+    ctx: Context<'gc>,
+    value: Value<'gc>,
+    type_id: TypeId,
+    type_path: &str,
+    registry: &TypeRegistry,
+) -> anyhow::Result<Box<dyn PartialReflect>> {
+    let type_info = registry
+        .get_type_info(type_id)
+        .ok_or_else(|| anyhow!("{type_path} isn't in the type registry"))?;
+    let TypeInfo::Enum(enum_info) = type_info else {
+        bail!("{type_path} isn't an enum");
+    };
+    let mut dynamic = match value {
+        Value::Nil => DynamicEnum::new("None", DynamicVariant::Unit),
+        value => {
+            let Some(VariantInfo::Tuple(some)) = enum_info.variant("Some") else {
+                bail!("{type_path} has no Some variant");
+            };
+            let inner = some.field_at(0).unwrap();
+            let inner = lua_to_owned_in(ctx, value, inner.type_id(), inner.type_path(), registry)?;
+            let mut tuple = DynamicTuple::default();
+            tuple.insert_boxed(inner);
+            DynamicEnum::new("Some", DynamicVariant::Tuple(tuple))
+        }
+    };
+    dynamic.set_represented_type(Some(type_info));
+    registry
+        .get_type_data::<ReflectFromReflect>(type_id)
+        .and_then(|from_reflect| from_reflect.from_reflect(&dynamic))
+        .map(|value| value.into_partial_reflect())
+        .ok_or_else(|| anyhow!("couldn't build a {type_path}"))
+}
+
+/// Like `get_sum_with(&self, other: f64) -> f64`, for error messages.
+pub fn signature(info: &FunctionInfo) -> String {
+    let args = info
+        .args()
+        .iter()
+        .map(|arg| {
+            let reference = match arg.ownership() {
+                Ownership::Ref => "&",
+                Ownership::Mut => "&mut ",
+                Ownership::Owned => "",
+            };
+            match arg.name() {
+                Some(name) => format!("{name}: {reference}{}", arg.type_path()),
+                None => format!("{reference}{}", arg.type_path()),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let name = info.name().map(|name| name.as_ref()).unwrap_or("function");
+    format!("{name}({args}) -> {}", info.return_info().type_path())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::call_overloads;
+    use crate::reflect_stuff::ObjectFunctionRegistry;
+    use bevy::prelude::Function;
+    use piccolo::{Lua, UserData};
+
+    fn add(a: f32, b: i32) -> f32 {
+        a + b as f32
+    }
+
+    fn scale(a: f32, by: Option<f32>) -> f32 {
+        a * by.unwrap_or(1.0)
+    }
+
+    fn bump(value: &mut f32) {
+        *value += 1.0;
+    }
+
+    fn pick_int(_: i32) -> i32 {
+        1
+    }
+
+    fn pick_string(_: String) -> i32 {
+        2
+    }
+
+    fn error_message<T>(result: anyhow::Result<T>) -> String {
+        result.err().unwrap().to_string()
+    }
+
+    #[test]
+    fn signatures() {
+        let add = add.into_function().with_name("add");
+        assert_eq!(signature(add.info()), "add(f32, i32) -> f32");
+    }
+
+    #[test]
+    fn argument_counts() {
+        Lua::core().enter(|ctx| {
+            let registry = AppTypeRegistry::default();
+            registry.write().register::<Option<f32>>();
+            ctx.set_global("__type_registry", UserData::new_static(&ctx, registry));
+
+            let add = add.into_function().with_name("add");
+            let err = error_message(lua_to_args(ctx, vec![Value::Number(1.0)], add.info()));
+            assert!(err.contains("takes 2 arguments, got 1"), "{err}");
+
+            // trailing options can be left out
+            let scale = scale.into_function().with_name("scale");
+            assert!(lua_to_args(ctx, vec![Value::Number(2.0)], scale.info()).is_ok());
+            let both = vec![Value::Number(2.0), Value::Number(3.0)];
+            assert!(lua_to_args(ctx, both, scale.info()).is_ok());
+            let err = error_message(lua_to_args(ctx, vec![], scale.info()));
+            assert!(err.contains("takes 1 to 2 arguments, got 0"), "{err}");
+            let three = vec![Value::Number(1.0); 3];
+            assert!(lua_to_args(ctx, three, scale.info()).is_err());
+        });
+    }
+
+    #[test]
+    fn integers_and_floats() {
+        Lua::core().enter(|ctx| {
+            let add = add.into_function().with_name("add");
+            let args = vec![Value::Integer(1), Value::Number(2.0)];
+            let args = lua_to_args(ctx, args, add.info()).ok().unwrap();
+            let LuaArg::Value(Some(b)) = &args[1] else {
+                panic!("expected a converted value");
+            };
+            assert_eq!(b.try_downcast_ref::<i32>(), Some(&2));
+
+            let args = vec![Value::Integer(1), Value::Number(2.5)];
+            let err = error_message(lua_to_args(ctx, args, add.info()));
+            assert!(err.contains("argument 2"), "{err}");
+            let args = vec![Value::Integer(1), Value::Integer(i64::MAX)];
+            assert!(lua_to_args(ctx, args, add.info()).is_err());
+        });
+    }
+
+    #[test]
+    fn read_only_values_arent_mut_arguments() {
+        Lua::core().enter(|ctx| {
+            let ptr_state = Rc::new(RefCell::new(PtrState::Valid));
+            let function_registry = Rc::new(RefCell::new(ObjectFunctionRegistry::default()));
+            let bump = bump.into_function().with_name("bump");
+            let mut value = 1.0f32;
+
+            let read_only =
+                ReflectPtr::new_ref(&value, ptr_state.clone(), function_registry.clone());
+            let read_only = Value::UserData(UserData::new_static(&ctx, read_only));
+            let err = error_message(lua_to_args(ctx, vec![read_only], bump.info()));
+            assert!(err.contains("read only"), "{err}");
+
+            let mutable = ReflectPtr::new_mut(&mut value, ptr_state, function_registry.clone());
+            let mutable = Value::UserData(UserData::new_static(&ctx, mutable));
+            assert!(call_overloads(ctx, &[bump], vec![mutable], &function_registry).is_ok());
+            assert_eq!(value, 2.0);
+        });
+    }
+
+    #[test]
+    fn first_fitting_overload_is_called() {
+        Lua::core().enter(|ctx| {
+            let function_registry = Rc::new(RefCell::new(ObjectFunctionRegistry::default()));
+            let overloads = [
+                pick_int.into_function().with_name("pick"),
+                pick_string.into_function().with_name("pick"),
+            ];
+            let call = |value: Value| {
+                call_overloads(ctx, &overloads, vec![value], &function_registry)
+                    .ok()
+                    .map(|values| values[0])
+            };
+            assert!(matches!(call(Value::Integer(5)), Some(Value::Integer(1))));
+            assert!(matches!(call(Value::Number(5.0)), Some(Value::Integer(1))));
+            let string = Value::String(piccolo::String::from_slice(&ctx, "five"));
+            assert!(matches!(call(string), Some(Value::Integer(2))));
+            assert!(call(Value::Number(5.5)).is_none());
+            assert!(call(Value::Boolean(true)).is_none());
+        });
+    }
+
+    fn first<'a>(a: &'a String, _: &String) -> &'a String {
+        a
+    }
+
+    #[test]
+    fn references_only_borrow_from_system_values() {
+        let function_registry = Rc::new(RefCell::new(ObjectFunctionRegistry::default()));
+        let first = first.into_function();
+        let ptr_state = Rc::new(RefCell::new(PtrState::Valid));
+        let (mut a, mut b) = ("a".to_string(), "b".to_string());
+        let a = ReflectPtr::new_mut(&mut a, ptr_state.clone(), function_registry.clone());
+        let b = ReflectPtr::new_mut(&mut b, ptr_state.clone(), function_registry.clone());
+        let borrowed =
+            borrowed_ptr_state([LuaArg::Ptr(&a), LuaArg::Ptr(&b)].as_slice(), first.info());
+        assert!(borrowed.is_some_and(|borrowed| Rc::ptr_eq(&borrowed, &ptr_state)));
+
+        // from another system's values
+        let mut c = "c".to_string();
+        let other_state = Rc::new(RefCell::new(PtrState::Valid));
+        let c = ReflectPtr::new_mut(&mut c, other_state, function_registry.clone());
+        let args = [LuaArg::Ptr(&a), LuaArg::Ptr(&c)];
+        assert!(borrowed_ptr_state(&args, first.info()).is_none());
+
+        // from a value converted for the call
+        let converted = LuaArg::Value(Some(Box::new("c".to_string())));
+        let args = [LuaArg::Ptr(&a), converted];
+        assert!(borrowed_ptr_state(&args, first.info()).is_none());
+
+        // from a boxed value lua can move out
+        let boxed = ReflectPtr::new_boxed(Box::new("d".to_string()), ptr_state, function_registry);
+        let args = [LuaArg::Ptr(&a), LuaArg::Ptr(&boxed)];
+        assert!(borrowed_ptr_state(&args, first.info()).is_none());
+    }
+
+    #[derive(Reflect)]
+    struct Token(u32);
+
+    fn spend(token: Token, _: i32) -> u32 {
+        token.0
+    }
+
+    fn spend_named(token: Token, _: String) -> u32 {
+        token.0 + 100
+    }
+
+    #[test]
+    fn values_move_once_an_overload_fits() {
+        Lua::core().enter(|ctx| {
+            let function_registry = Rc::new(RefCell::new(ObjectFunctionRegistry::default()));
+            let overloads = [
+                spend.into_function().with_name("spend"),
+                spend_named.into_function().with_name("spend"),
+            ];
+            // can't be copied without the type registry, so it's moved
+            let token = ReflectPtr::new_boxed(
+                Box::new(Token(1)),
+                Rc::new(RefCell::new(PtrState::Valid)),
+                function_registry.clone(),
+            );
+            let value = Value::UserData(UserData::new_static(&ctx, token.clone()));
+
+            let args = vec![value, Value::Boolean(true)];
+            assert!(call_overloads(ctx, &overloads, args, &function_registry).is_err());
+            assert!(token.try_field_value_ref().is_ok());
+
+            let name = Value::String(piccolo::String::from_slice(&ctx, "name"));
+            let returned = call_overloads(ctx, &overloads, vec![value, name], &function_registry)
+                .ok()
+                .map(|values| values[0]);
+            assert!(matches!(returned, Some(Value::Integer(101))));
+            assert!(token.try_field_value_ref().is_err());
+        });
+    }
+}
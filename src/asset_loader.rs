@@ -1,4 +1,4 @@
-use crate::reflect_stuff::LuaSystem;
+use crate::reflect_stuff::LuaSystems;
 use bevy::asset::io::Reader;
 use bevy::asset::{AssetLoader, AssetPath, LoadContext, UntypedAssetId, VisitAssetDependencies};
 use bevy::prelude::*;
@@ -7,7 +7,7 @@ use flume::{Receiver, Sender};
 use send_wrapper::SendWrapper;
 
 pub struct LuaAssetLoader {
-    pub lua_script_rx: Receiver<LuaScript>,
+    pub lua_script_rx: Receiver<anyhow::Result<LuaScript>>,
     pub lua_script_bytes_tx: Sender<(Vec<u8>, AssetPath<'static>)>,
 }
 
@@ -28,7 +28,7 @@ impl AssetLoader for LuaAssetLoader {
             self.lua_script_bytes_tx
                 .send((bytes, load_context.asset_path().clone()))
                 .unwrap();
-            let lua_script = self.lua_script_rx.recv_async().await?;
+            let lua_script = self.lua_script_rx.recv_async().await??;
             Ok(lua_script)
         })
     }
@@ -36,7 +36,7 @@ impl AssetLoader for LuaAssetLoader {
 
 #[derive(TypePath)]
 pub struct LuaScript {
-    pub systems: SendWrapper<Vec<LuaSystem>>,
+    pub systems: SendWrapper<LuaSystems>,
 }
 
 impl VisitAssetDependencies for LuaScript {
@@ -47,7 +47,8 @@ impl Asset for LuaScript {}
 
 #[derive(Resource)]
 pub struct LuaAssetCommunicator {
-    pub lua_script_tx: Sender<LuaScript>,
+    /// A script that errors while it runs fails its load, a reloaded one keeps the old systems.
+    pub lua_script_tx: Sender<anyhow::Result<LuaScript>>,
     pub lua_script_bytes_rx: Receiver<(Vec<u8>, AssetPath<'static>)>,
 }
 
@@ -0,0 +1,222 @@
+use crate::args_stuff::lua_to_owned;
+use crate::reflect_stuff::{PathKey, ReflectPtr};
+use crate::return_stuff::{concrete, owned_to_lua};
+use crate::userdata_stuff::UserDataPtr;
+use anyhow::{anyhow, bail};
+use bevy::prelude::*;
+use bevy::reflect::{ReflectMut, ReflectRef};
+use piccolo::{Callback, CallbackReturn, Context, UserData, Value};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+/// `#value`, the number of items in lists, arrays, maps and sets or of fields in structs,
+/// tuples and enum variants.
+pub fn len(reflect: &dyn PartialReflect) -> anyhow::Result<usize> {
+    Ok(match reflect.reflect_ref() {
+        ReflectRef::List(list) => list.len(),
+        ReflectRef::Array(array) => array.len(),
+        ReflectRef::Map(map) => map.len(),
+        ReflectRef::Set(set) => set.len(),
+        ReflectRef::Struct(s) => s.field_len(),
+        ReflectRef::TupleStruct(s) => s.field_len(),
+        ReflectRef::Tuple(tuple) => tuple.field_len(),
+        ReflectRef::Enum(e) => e.field_len(),
+        _ => bail!("{} has no length", reflect.reflect_type_path()),
+    })
+}
+
+enum PairsItem {
+    Key(PathKey),
+    /// Set elements can't be pointed into, so they're handed out as copies.
+    Element(Box<dyn PartialReflect>),
+}
+
+pub struct PairsState {
+    parent: ReflectPtr,
+    items: VecDeque<PairsItem>,
+}
+
+/// The `__pairs` of `ReflectPtr`s. Lists, arrays and tuples give their indices, structs and
+/// struct variants their field names and maps their keys, each with a `ReflectPtr` to the
+/// value sharing the parent's `ptr_state`. Sets give each element and `true`.
+pub fn pairs<'gc>(ctx: Context<'gc>) -> Callback<'gc> {
+    Callback::from_fn(&ctx, |ctx, _fuel, mut stack| {
+        let this: &ReflectPtr = stack.consume(ctx)?;
+        let items = pairs_items(this.try_field_value_ref()?.as_partial_reflect())?;
+        let state = UserData::new_static(
+            &ctx,
+            RefCell::new(PairsState {
+                parent: this.clone(),
+                items,
+            }),
+        );
+        stack.replace(ctx, (pairs_next(ctx), Value::UserData(state)));
+        Ok(CallbackReturn::Return)
+    })
+}
+
+fn pairs_items(reflect: &dyn PartialReflect) -> anyhow::Result<VecDeque<PairsItem>> {
+    let indices = |len: usize| -> VecDeque<PairsItem> {
+        (1..=len as i64)
+            .map(|i| PairsItem::Key(PathKey::Index(i)))
+            .collect()
+    };
+    Ok(match reflect.reflect_ref() {
+        ReflectRef::List(list) => indices(list.len()),
+        ReflectRef::Array(array) => indices(array.len()),
+        ReflectRef::TupleStruct(s) => indices(s.field_len()),
+        ReflectRef::Tuple(tuple) => indices(tuple.field_len()),
+        ReflectRef::Struct(s) => (0..s.field_len())
+            .map(|i| PairsItem::Key(PathKey::Name(s.name_at(i).unwrap().to_string())))
+            .collect(),
+        ReflectRef::Map(map) => map
+            .iter()
+            .map(|(key, _)| {
+                PathKey::from_reflect(key)
+                    .map(PairsItem::Key)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "can't iterate over {}, its keys aren't strings or integers",
+                            reflect.reflect_type_path()
+                        )
+                    })
+            })
+            .collect::<anyhow::Result<_>>()?,
+        ReflectRef::Enum(e) => (0..e.field_len())
+            .map(|i| match e.name_at(i) {
+                Some(name) => PairsItem::Key(PathKey::Name(name.to_string())),
+                None => PairsItem::Key(PathKey::Index(i as i64 + 1)),
+            })
+            .collect(),
+        ReflectRef::Set(set) => set
+            .iter()
+            .map(|element| PairsItem::Element(element.clone_value()))
+            .collect(),
+        _ => bail!("can't iterate over a {}", reflect.reflect_type_path()),
+    })
+}
+
+fn pairs_next<'gc>(ctx: Context<'gc>) -> Callback<'gc> {
+    Callback::from_fn(&ctx, |ctx, _fuel, mut stack| {
+        let (state, _control): (UserData, Value) = stack.consume(ctx)?;
+        let mut state = state.downcast_static::<RefCell<PairsState>>()?.borrow_mut();
+        let Some(item) = state.items.pop_front() else {
+            return Ok(CallbackReturn::Return);
+        };
+        match item {
+            PairsItem::Key(key) => {
+                let child = state.parent.child(key.clone())?;
+                stack.push_back(key.into_lua(ctx));
+                stack.push_back(child.into_value(&ctx));
+            }
+            PairsItem::Element(element) => {
+                let registry = state.parent.function_registry();
+                stack.push_back(owned_to_lua(ctx, concrete(ctx, element), &registry)?);
+                stack.push_back(Value::Boolean(true));
+            }
+        }
+        Ok(CallbackReturn::Return)
+    })
+}
+
+/// `push`, `insert` and `remove` on lists, `insert` and `remove` on maps. On maps they hide
+/// entries with the same names.
+pub fn collection_method<'gc>(
+    ctx: Context<'gc>,
+    reflect: &dyn Reflect,
+    name: &str,
+) -> Option<Callback<'gc>> {
+    let callback = match (reflect.reflect_ref(), name) {
+        (ReflectRef::List(_), "push") => Callback::from_fn(&ctx, |ctx, _fuel, mut stack| {
+            let (this, value): (&ReflectPtr, Value) = stack.consume(ctx)?;
+            let list = list_mut(this)?;
+            let item = list_item(ctx, list, value)?;
+            list.push(item);
+            Ok(CallbackReturn::Return)
+        }),
+        (ReflectRef::List(_), "insert") => Callback::from_fn(&ctx, |ctx, _fuel, mut stack| {
+            let (this, index, value): (&ReflectPtr, i64, Value) = stack.consume(ctx)?;
+            let list = list_mut(this)?;
+            let len = list.len();
+            if index < 1 || index as u64 > len as u64 + 1 {
+                return Err(anyhow!("can't insert at {index}, the list has {len} items").into());
+            }
+            let item = list_item(ctx, list, value)?;
+            list.insert(index as usize - 1, item);
+            Ok(CallbackReturn::Return)
+        }),
+        (ReflectRef::List(_), "remove") => Callback::from_fn(&ctx, |ctx, _fuel, mut stack| {
+            let (this, index): (&ReflectPtr, i64) = stack.consume(ctx)?;
+            let list = list_mut(this)?;
+            let len = list.len();
+            if index < 1 || index as u64 > len as u64 {
+                return Err(anyhow!("can't remove {index}, the list has {len} items").into());
+            }
+            let removed = list.remove(index as usize - 1);
+            stack.replace(ctx, owned_to_lua(ctx, removed, &this.function_registry())?);
+            Ok(CallbackReturn::Return)
+        }),
+        (ReflectRef::Map(_), "insert") => Callback::from_fn(&ctx, |ctx, _fuel, mut stack| {
+            let (this, key, value): (&ReflectPtr, Value, Value) = stack.consume(ctx)?;
+            let map = map_mut(this)?;
+            let (key, value) = map_entry(ctx, map, key, Some(value))?;
+            let old = map.insert_boxed(key, value.unwrap());
+            if let Some(old) = old {
+                stack.replace(ctx, owned_to_lua(ctx, old, &this.function_registry())?);
+            }
+            Ok(CallbackReturn::Return)
+        }),
+        (ReflectRef::Map(_), "remove") => Callback::from_fn(&ctx, |ctx, _fuel, mut stack| {
+            let (this, key): (&ReflectPtr, Value) = stack.consume(ctx)?;
+            let map = map_mut(this)?;
+            let (key, _) = map_entry(ctx, map, key, None)?;
+            if let Some(removed) = map.remove(&*key) {
+                stack.replace(ctx, owned_to_lua(ctx, removed, &this.function_registry())?);
+            }
+            Ok(CallbackReturn::Return)
+        }),
+        _ => return None,
+    };
+    Some(callback)
+}
+
+fn list_mut(this: &ReflectPtr) -> anyhow::Result<&mut dyn List> {
+    match this.try_field_value_mut()?.reflect_mut() {
+        ReflectMut::List(list) => Ok(list),
+        _ => bail!("expected a list"),
+    }
+}
+
+fn map_mut(this: &ReflectPtr) -> anyhow::Result<&mut dyn Map> {
+    match this.try_field_value_mut()?.reflect_mut() {
+        ReflectMut::Map(map) => Ok(map),
+        _ => bail!("expected a map"),
+    }
+}
+
+fn list_item<'gc>(
+    ctx: Context<'gc>,
+    list: &dyn List,
+    value: Value<'gc>,
+) -> anyhow::Result<Box<dyn PartialReflect>> {
+    let info = list
+        .get_represented_list_info()
+        .ok_or_else(|| anyhow!("{} has no type info", list.reflect_type_path()))?;
+    lua_to_owned(ctx, value, info.item_ty().id(), info.item_ty().path())
+}
+
+fn map_entry<'gc>(
+    ctx: Context<'gc>,
+    map: &dyn Map,
+    key: Value<'gc>,
+    value: Option<Value<'gc>>,
+) -> anyhow::Result<(Box<dyn PartialReflect>, Option<Box<dyn PartialReflect>>)> {
+    let info = map
+        .get_represented_map_info()
+        .ok_or_else(|| anyhow!("{} has no type info", map.reflect_type_path()))?;
+    let key = lua_to_owned(ctx, key, info.key_ty().id(), info.key_ty().path())?;
+    let value = value
+        .map(|value| lua_to_owned(ctx, value, info.value_ty().id(), info.value_ty().path()))
+        .transpose()?;
+    Ok((key, value))
+}
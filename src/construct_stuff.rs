@@ -0,0 +1,383 @@
+use crate::args_stuff::lua_to_owned_in;
+use crate::reflect_stuff::{ObjectFunctionRegistry, PtrState, ReflectPtr};
+use crate::userdata_stuff::UserDataPtr;
+use anyhow::{anyhow, bail};
+use bevy::prelude::*;
+use bevy::reflect::{
+    DynamicEnum, DynamicStruct, DynamicTupleStruct, DynamicVariant, ReflectFromReflect, TypeInfo,
+    TypeRegistration, TypeRegistry, VariantInfo,
+};
+use piccolo::{Callback, CallbackReturn, Context, Table, Value};
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The `__call` of a type's namespace table, so `Transform{ translation = Vec3{x=1} }` builds
+/// a boxed `ReflectPtr` usable with `commands:spawn`.
+pub fn constructor<'gc>(
+    ctx: Context<'gc>,
+    type_id: TypeId,
+    registry: AppTypeRegistry,
+    object_function_registry: Rc<RefCell<ObjectFunctionRegistry>>,
+) -> Callback<'gc> {
+    Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+        let (_namespace, fields): (Table, Option<Table>) = stack.consume(ctx)?;
+        let registry = registry.read();
+        let type_info = registry
+            .get_type_info(type_id)
+            .ok_or_else(|| anyhow!("type isn't in the type registry"))?;
+        let fields = fields.unwrap_or_else(|| Table::new(&ctx));
+        let value = table_to_reflect(ctx, fields, type_info, &registry)?;
+        stack.replace(
+            ctx,
+            ReflectPtr::new_boxed(
+                value,
+                Rc::new(RefCell::new(PtrState::Valid)),
+                object_function_registry.clone(),
+            )
+            .into_value(&ctx),
+        );
+        Ok(CallbackReturn::Return)
+    })
+}
+
+/// `default()` on a type's namespace table, for every type with `ReflectDefault`.
+pub fn default_constructor<'gc>(
+    ctx: Context<'gc>,
+    type_id: TypeId,
+    registry: AppTypeRegistry,
+    object_function_registry: Rc<RefCell<ObjectFunctionRegistry>>,
+) -> Callback<'gc> {
+    Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+        let registry = registry.read();
+        let reflect_default = registry
+            .get_type_data::<ReflectDefault>(type_id)
+            .ok_or_else(|| anyhow!("type has no ReflectDefault"))?;
+        stack.replace(
+            ctx,
+            ReflectPtr::new_boxed(
+                reflect_default.default(),
+                Rc::new(RefCell::new(PtrState::Valid)),
+                object_function_registry.clone(),
+            )
+            .into_value(&ctx),
+        );
+        Ok(CallbackReturn::Return)
+    })
+}
+
+/// Whether `constructor` knows how to build this type from a table.
+pub fn is_constructible(registration: &TypeRegistration) -> bool {
+    matches!(
+        registration.type_info(),
+        TypeInfo::Struct(_) | TypeInfo::TupleStruct(_)
+    ) && (registration.data::<ReflectDefault>().is_some()
+        || registration.data::<ReflectFromReflect>().is_some())
+}
+
+/// The registry `register_components_and_markers` puts in lua, `None` before that has run.
+pub fn type_registry(ctx: Context) -> Option<AppTypeRegistry> {
+    let Value::UserData(registry) = ctx
+        .globals()
+        .get::<_, Value>(ctx, "__type_registry")
+        .unwrap()
+    else {
+        return None;
+    };
+    registry.downcast_static::<AppTypeRegistry>().ok().cloned()
+}
+
+/// The part of `lua_to_owned` that goes by the shape of the type: another `ReflectPtr` of
+/// it, a unit enum variant by name, or a struct from a table.
+pub fn lua_to_reflect<'gc>(
+    ctx: Context<'gc>,
+    value: Value<'gc>,
+    type_info: &'static TypeInfo,
+    registry: &TypeRegistry,
+) -> anyhow::Result<Box<dyn PartialReflect>> {
+    if let Value::UserData(user_data) = value {
+        if let Ok(reflect_ptr) = user_data.downcast_static::<ReflectPtr>() {
+            let reflect = reflect_ptr.try_field_value_ref()?;
+            if reflect.reflect_type_info().type_id() != type_info.type_id() {
+                bail!(
+                    "expected a {}, got a {}",
+                    type_info.type_path(),
+                    reflect.reflect_type_path()
+                );
+            }
+            return Ok(reflect.clone_value());
+        }
+    }
+    match value {
+        Value::String(name) if matches!(type_info, TypeInfo::Enum(_)) => {
+            unit_variant(type_info, name.to_str()?, registry)
+        }
+        Value::Table(table)
+            if matches!(type_info, TypeInfo::Struct(_) | TypeInfo::TupleStruct(_)) =>
+        {
+            Ok(table_to_reflect(ctx, table, type_info, registry)?.into_partial_reflect())
+        }
+        value => bail!(
+            "can't turn a lua {} into a {}",
+            value.type_name(),
+            type_info.type_path()
+        ),
+    }
+}
+
+/// Any lua number as an int or float of the type `type_id`, if it's one and the number fits.
+pub fn number_to_reflect(
+    value: Value,
+    type_id: TypeId,
+) -> anyhow::Result<Option<Box<dyn PartialReflect>>> {
+    let (integer, float) = match value {
+        Value::Integer(integer) => (Some(integer), integer as f64),
+        Value::Number(float) => {
+            // `as` saturates, so a whole float outside of i64 would quietly become its min or max
+            let fits = (i64::MIN as f64..i64::MAX as f64).contains(&float);
+            (
+                (float.fract() == 0.0 && fits).then_some(float as i64),
+                float,
+            )
+        }
+        _ => return Ok(None),
+    };
+    macro_rules! integers {
+        ($($int:ty),*) => {$(
+            if type_id == TypeId::of::<$int>() {
+                let integer = integer.ok_or_else(|| {
+                    if float.fract() == 0.0 {
+                        anyhow!("{float} doesn't fit in a {}", stringify!($int))
+                    } else {
+                        anyhow!("expected an integer for a {}, got {float}", stringify!($int))
+                    }
+                })?;
+                let integer = <$int>::try_from(integer)
+                    .map_err(|_| anyhow!("{integer} doesn't fit in a {}", stringify!($int)))?;
+                return Ok(Some(Box::new(integer)));
+            }
+        )*};
+    }
+    integers!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+    if type_id == TypeId::of::<f32>() {
+        // infinities and NaN stay as they are, finite numbers shouldn't turn into them
+        if float.is_finite() && !(float as f32).is_finite() {
+            bail!("{float} doesn't fit in a f32");
+        }
+        return Ok(Some(Box::new(float as f32)));
+    }
+    if type_id == TypeId::of::<f64>() {
+        return Ok(Some(Box::new(float)));
+    }
+    Ok(None)
+}
+
+/// A unit variant of an enum from its name, like `"Hidden"` for `Visibility::Hidden`.
+pub fn unit_variant(
+    type_info: &'static TypeInfo,
+    name: &str,
+    registry: &TypeRegistry,
+) -> anyhow::Result<Box<dyn PartialReflect>> {
+    let TypeInfo::Enum(enum_info) = type_info else {
+        bail!("{} isn't an enum", type_info.type_path());
+    };
+    match enum_info.variant(name) {
+        Some(VariantInfo::Unit(_)) => {}
+        Some(_) => bail!("{}::{name} isn't a unit variant", type_info.type_path()),
+        None => bail!(
+            "{} has no variant {name}, only {}",
+            type_info.type_path(),
+            enum_info.variant_names().join(", ")
+        ),
+    }
+    let mut dynamic = DynamicEnum::new(name, DynamicVariant::Unit);
+    dynamic.set_represented_type(Some(type_info));
+    let value = registry
+        .get_type_data::<ReflectFromReflect>(type_info.type_id())
+        .and_then(|from_reflect| from_reflect.from_reflect(&dynamic));
+    match value {
+        Some(value) => Ok(value.into_partial_reflect()),
+        None => Ok(Box::new(dynamic)),
+    }
+}
+
+/// Builds a struct from its named fields, or a tuple struct from a sequence. Fields left out
+/// come from `ReflectDefault`, without it every field has to be given.
+pub fn table_to_reflect<'gc>(
+    ctx: Context<'gc>,
+    table: Table<'gc>,
+    type_info: &'static TypeInfo,
+    registry: &TypeRegistry,
+) -> anyhow::Result<Box<dyn Reflect>> {
+    let registration = registry
+        .get(type_info.type_id())
+        .ok_or_else(|| anyhow!("{} isn't in the type registry", type_info.type_path()))?;
+    match type_info {
+        TypeInfo::Struct(struct_info) => {
+            let mut dynamic = DynamicStruct::default();
+            dynamic.set_represented_type(Some(type_info));
+            for (key, value) in table {
+                let Value::String(name) = key else {
+                    bail!(
+                        "{} has named fields, got a {} key",
+                        type_info.type_path(),
+                        key.type_name()
+                    );
+                };
+                let name = name.to_str()?;
+                let field = struct_info
+                    .field(name)
+                    .ok_or_else(|| anyhow!("{} has no field {name}", type_info.type_path()))?;
+                let value =
+                    lua_to_owned_in(ctx, value, field.type_id(), field.type_path(), registry)?;
+                dynamic.insert_boxed(name, value);
+            }
+            finish(registration, &dynamic)
+        }
+        TypeInfo::TupleStruct(tuple_struct_info) => {
+            let mut dynamic = DynamicTupleStruct::default();
+            dynamic.set_represented_type(Some(type_info));
+            for field in tuple_struct_info.iter() {
+                let value = table.get::<_, Value>(ctx, field.index() as i64 + 1)?;
+                if matches!(value, Value::Nil) {
+                    break;
+                }
+                let value =
+                    lua_to_owned_in(ctx, value, field.type_id(), field.type_path(), registry)?;
+                dynamic.insert_boxed(value);
+            }
+            finish(registration, &dynamic)
+        }
+        _ => bail!("{} can't be built from a table", type_info.type_path()),
+    }
+}
+
+fn finish(
+    registration: &TypeRegistration,
+    dynamic: &dyn PartialReflect,
+) -> anyhow::Result<Box<dyn Reflect>> {
+    let type_path = registration.type_info().type_path();
+    if let Some(reflect_default) = registration.data::<ReflectDefault>() {
+        let mut value = reflect_default.default();
+        value.try_apply(dynamic)?;
+        return Ok(value);
+    }
+    if let Some(reflect_from_reflect) = registration.data::<ReflectFromReflect>() {
+        return reflect_from_reflect.from_reflect(dynamic).ok_or_else(|| {
+            anyhow!("{type_path} has no ReflectDefault, so every field has to be given")
+        });
+    }
+    bail!("{type_path} has neither ReflectDefault nor ReflectFromReflect")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::reflect::Typed;
+    use piccolo::Lua;
+
+    fn number<T: PartialReflect + Clone>(value: Value) -> anyhow::Result<T> {
+        let reflect = number_to_reflect(value, TypeId::of::<T>())?.unwrap();
+        Ok(reflect.try_downcast_ref::<T>().unwrap().clone())
+    }
+
+    #[test]
+    fn whole_floats_become_integers() {
+        assert_eq!(number::<i32>(Value::Number(3.0)).unwrap(), 3);
+        assert_eq!(number::<u8>(Value::Integer(255)).unwrap(), 255);
+        assert_eq!(number::<f32>(Value::Integer(2)).unwrap(), 2.0);
+        assert!(number::<i32>(Value::Number(3.5)).is_err());
+    }
+
+    #[test]
+    fn integers_out_of_range_error() {
+        assert!(number::<u8>(Value::Integer(256)).is_err());
+        assert!(number::<u32>(Value::Integer(-1)).is_err());
+        assert!(number::<i64>(Value::Number(1e30)).is_err());
+        assert!(number::<i64>(Value::Number(-1e30)).is_err());
+        assert!(number::<i64>(Value::Number(9223372036854775808.0)).is_err());
+        assert_eq!(
+            number::<i64>(Value::Number(-9223372036854775808.0)).unwrap(),
+            i64::MIN
+        );
+    }
+
+    #[test]
+    fn floats_out_of_f32_range_error() {
+        assert!(number::<f32>(Value::Number(1e300)).is_err());
+        assert!(number::<f32>(Value::Number(f64::INFINITY))
+            .unwrap()
+            .is_infinite());
+        assert_eq!(number::<f64>(Value::Number(1e300)).unwrap(), 1e300);
+    }
+
+    #[test]
+    fn other_types_are_skipped() {
+        assert!(number_to_reflect(Value::Integer(1), TypeId::of::<String>())
+            .unwrap()
+            .is_none());
+        assert!(number_to_reflect(Value::Nil, TypeId::of::<i32>())
+            .unwrap()
+            .is_none());
+    }
+
+    #[derive(Reflect, Default, Debug, PartialEq)]
+    #[reflect(Default)]
+    struct Stats {
+        speed: f32,
+        level: u8,
+        mode: Mode,
+    }
+
+    #[derive(Reflect, Default, Debug, PartialEq)]
+    enum Mode {
+        #[default]
+        Slow,
+        Fast,
+    }
+
+    #[test]
+    fn structs_from_tables() {
+        Lua::core().enter(|ctx| {
+            let mut registry = TypeRegistry::default();
+            registry.register::<Stats>();
+            let stats = |fields: &[(&str, Value)]| {
+                let table = Table::new(&ctx);
+                for (name, value) in fields {
+                    let name = piccolo::String::from_slice(&ctx, name);
+                    table.set(ctx, name, *value).unwrap();
+                }
+                table_to_reflect(ctx, table, Stats::type_info(), &registry)
+                    .map(|stats| stats.take::<Stats>().ok().unwrap())
+            };
+            let fast = Value::String(piccolo::String::from_slice(&ctx, "Fast"));
+
+            let built = stats(&[
+                ("speed", Value::Integer(2)),
+                ("level", Value::Number(3.0)),
+                ("mode", fast),
+            ]);
+            assert_eq!(
+                built.ok(),
+                Some(Stats {
+                    speed: 2.0,
+                    level: 3,
+                    mode: Mode::Fast
+                })
+            );
+            // left out fields are the default
+            assert_eq!(
+                stats(&[("speed", Value::Number(0.5))]).ok(),
+                Some(Stats {
+                    speed: 0.5,
+                    ..default()
+                })
+            );
+            assert!(stats(&[("level", Value::Integer(300))]).is_err());
+            assert!(stats(&[("level", Value::Number(1.5))]).is_err());
+            assert!(stats(&[("height", Value::Integer(1))]).is_err());
+            let sideways = Value::String(piccolo::String::from_slice(&ctx, "Sideways"));
+            assert!(stats(&[("mode", sideways)]).is_err());
+        });
+    }
+}
@@ -0,0 +1,203 @@
+use crate::args_stuff::lua_to_owned_in;
+use crate::reflect_stuff::{ObjectFunctionRegistry, PtrState, ReflectPtr};
+use crate::userdata_stuff::UserDataPtr;
+use anyhow::{anyhow, bail};
+use bevy::prelude::*;
+use bevy::reflect::{
+    DynamicEnum, DynamicStruct, DynamicTuple, DynamicVariant, ReflectFromReflect, ReflectRef,
+    TypeInfo, TypeRegistry, VariantInfo,
+};
+use piccolo::{Callback, CallbackReturn, Context, Table, Value};
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The `__index` of an enum's namespace table. Unit variants are values, `MyEnum.Idle`, and
+/// the others are built from a table, `MyEnum.Attack{ target = e }` or `MyEnum.Move{ 1, 2 }`.
+pub fn variant_index<'gc>(
+    ctx: Context<'gc>,
+    type_id: TypeId,
+    registry: AppTypeRegistry,
+    object_function_registry: Rc<RefCell<ObjectFunctionRegistry>>,
+) -> Callback<'gc> {
+    Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+        let (_namespace, name): (Table, Value) = stack.consume(ctx)?;
+        let Value::String(name) = name else {
+            return Ok(CallbackReturn::Return);
+        };
+        let name = name.to_str()?.to_string();
+        let unit = {
+            let registry = registry.read();
+            let Some(TypeInfo::Enum(enum_info)) = registry.get_type_info(type_id) else {
+                return Err(anyhow!("enum isn't in the type registry").into());
+            };
+            match enum_info.variant(&name) {
+                Some(VariantInfo::Unit(_)) => true,
+                Some(_) => false,
+                None => return Ok(CallbackReturn::Return),
+            }
+        };
+        if unit {
+            let value = build_variant(ctx, type_id, &name, None, &registry.read())?;
+            stack.push_back(boxed(ctx, value, &object_function_registry));
+            return Ok(CallbackReturn::Return);
+        }
+        let registry = registry.clone();
+        let object_function_registry = object_function_registry.clone();
+        let constructor = Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+            let fields: Option<Table> = stack.consume(ctx)?;
+            let value = build_variant(ctx, type_id, &name, fields, &registry.read())?;
+            stack.push_back(boxed(ctx, value, &object_function_registry));
+            Ok(CallbackReturn::Return)
+        });
+        stack.push_back(constructor.into());
+        Ok(CallbackReturn::Return)
+    })
+}
+
+fn boxed<'gc>(
+    ctx: Context<'gc>,
+    value: Box<dyn Reflect>,
+    object_function_registry: &Rc<RefCell<ObjectFunctionRegistry>>,
+) -> Value<'gc> {
+    ReflectPtr::new_boxed(
+        value,
+        Rc::new(RefCell::new(PtrState::Valid)),
+        object_function_registry.clone(),
+    )
+    .into_value(&ctx)
+}
+
+/// Builds the variant `name` of the enum `type_id`, struct variants from named fields and
+/// tuple variants from a sequence. Every field has to be given.
+pub fn build_variant<'gc>(
+    ctx: Context<'gc>,
+    type_id: TypeId,
+    name: &str,
+    fields: Option<Table<'gc>>,
+    registry: &TypeRegistry,
+) -> anyhow::Result<Box<dyn Reflect>> {
+    let type_info = registry
+        .get_type_info(type_id)
+        .ok_or_else(|| anyhow!("enum isn't in the type registry"))?;
+    let type_path = type_info.type_path();
+    let TypeInfo::Enum(enum_info) = type_info else {
+        bail!("{type_path} isn't an enum");
+    };
+    let fields = fields.unwrap_or_else(|| Table::new(&ctx));
+    let variant = match enum_info.variant(name) {
+        Some(VariantInfo::Unit(_)) => DynamicVariant::Unit,
+        Some(VariantInfo::Struct(variant_info)) => {
+            let mut dynamic = DynamicStruct::default();
+            for (key, value) in fields {
+                let Value::String(field) = key else {
+                    bail!(
+                        "{type_path}::{name} has named fields, got a {} key",
+                        key.type_name()
+                    );
+                };
+                let field = field.to_str()?;
+                let info = variant_info
+                    .field(field)
+                    .ok_or_else(|| anyhow!("{type_path}::{name} has no field {field}"))?;
+                let value =
+                    lua_to_owned_in(ctx, value, info.type_id(), info.type_path(), registry)?;
+                dynamic.insert_boxed(field, value);
+            }
+            DynamicVariant::Struct(dynamic)
+        }
+        Some(VariantInfo::Tuple(variant_info)) => {
+            let mut dynamic = DynamicTuple::default();
+            for info in variant_info.iter() {
+                let value = fields.get::<_, Value>(ctx, info.index() as i64 + 1)?;
+                let value =
+                    lua_to_owned_in(ctx, value, info.type_id(), info.type_path(), registry)?;
+                dynamic.insert_boxed(value);
+            }
+            DynamicVariant::Tuple(dynamic)
+        }
+        None => bail!("{type_path} has no variant {name}"),
+    };
+    let mut dynamic = DynamicEnum::new(name, variant);
+    dynamic.set_represented_type(Some(type_info));
+    registry
+        .get_type_data::<ReflectFromReflect>(type_id)
+        .ok_or_else(|| anyhow!("{type_path} can't be built without ReflectFromReflect"))?
+        .from_reflect(&dynamic)
+        .ok_or_else(|| anyhow!("couldn't build a {type_path}::{name}"))
+}
+
+/// `value:variant()`, the name of the variant an enum is.
+pub fn enum_method<'gc>(
+    ctx: Context<'gc>,
+    reflect: &dyn Reflect,
+    name: &str,
+) -> Option<Callback<'gc>> {
+    if name != "variant" || !matches!(reflect.reflect_ref(), ReflectRef::Enum(_)) {
+        return None;
+    }
+    Some(Callback::from_fn(&ctx, |ctx, _fuel, mut stack| {
+        let this: &ReflectPtr = stack.consume(ctx)?;
+        let ReflectRef::Enum(value) = this.try_field_value_ref()?.reflect_ref() else {
+            return Err(anyhow!("expected an enum").into());
+        };
+        let name = piccolo::String::from_slice(&ctx, value.variant_name());
+        stack.push_back(Value::String(name));
+        Ok(CallbackReturn::Return)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use piccolo::Lua;
+
+    #[derive(Reflect, Debug, PartialEq)]
+    enum Shape {
+        Point,
+        Circle(f32),
+        Rect { w: f32, h: u8 },
+    }
+
+    #[test]
+    fn variants_from_tables() {
+        Lua::core().enter(|ctx| {
+            let mut registry = TypeRegistry::default();
+            registry.register::<Shape>();
+            let build = |name: &str, fields: Option<Table>| {
+                build_variant(ctx, TypeId::of::<Shape>(), name, fields, &registry)
+                    .map(|shape| shape.take::<Shape>().ok().unwrap())
+            };
+            let table = |values: &[(&str, Value)]| {
+                let table = Table::new(&ctx);
+                for (key, value) in values {
+                    match key.parse::<i64>() {
+                        Ok(i) => table.set(ctx, i, *value).unwrap(),
+                        Err(_) => {
+                            let key = piccolo::String::from_slice(&ctx, key);
+                            table.set(ctx, key, *value).unwrap()
+                        }
+                    };
+                }
+                table
+            };
+
+            assert_eq!(build("Point", None).ok(), Some(Shape::Point));
+            let circle = table(&[("1", Value::Integer(2))]);
+            assert_eq!(build("Circle", Some(circle)).ok(), Some(Shape::Circle(2.0)));
+            let rect = table(&[("w", Value::Number(1.5)), ("h", Value::Number(2.0))]);
+            assert_eq!(
+                build("Rect", Some(rect)).ok(),
+                Some(Shape::Rect { w: 1.5, h: 2 })
+            );
+
+            // fields get the same conversions and checks as function arguments
+            let too_big = table(&[("w", Value::Integer(1)), ("h", Value::Integer(256))]);
+            assert!(build("Rect", Some(too_big)).is_err());
+            assert!(build("Circle", None).is_err());
+            let unknown = table(&[("r", Value::Integer(1))]);
+            assert!(build("Rect", Some(unknown)).is_err());
+            assert!(build("Triangle", None).is_err());
+        });
+    }
+}
@@ -1,28 +1,40 @@
+mod args_stuff;
 pub mod asset_loader;
 mod bevy_wrapper;
+mod collection_stuff;
+mod construct_stuff;
+mod enum_stuff;
+mod ops_stuff;
 mod reflect_stuff;
+mod return_stuff;
+mod system_stuff;
 pub mod userdata_stuff;
 
+use crate::args_stuff::{borrowed_ptr_state, lua_to_args, signature};
 use crate::asset_loader::{LuaAssetCommunicator, LuaAssetLoader, LuaScript};
+use crate::ops_stuff::register_glam_operators;
 use crate::reflect_stuff::{
-    ComponentType, ObjectFunctionRegistry, PtrState, ReflectPlugin, ReflectPtr, ReflectType,
+    forget_despawned_entities, ComponentType, LuaEntity, LuaQuery, LuaSystemSets,
+    ObjectFunctionRegistry, PtrState, QueryTerm, ReflectPlugin, ReflectPtr, ReflectType,
     SystemParameter, WorldMut,
 };
+use crate::return_stuff::return_to_lua;
+use crate::system_stuff::install_lua_systems;
 use crate::userdata_stuff::{UserDataPtr, ValueExt};
-use bevy::ecs::component::ComponentId;
-use bevy::ecs::system::SystemBuffer;
-use bevy::ecs::world::CommandQueue;
+use bevy::ecs::component::Tick;
+use bevy::ecs::entity::Entities;
+use bevy::ecs::system::{SystemBuffer, SystemChangeTick, SystemMeta};
+use bevy::ecs::world::{CommandQueue, FilteredEntityMut};
 use bevy::prelude::*;
 use bevy::ptr::OwningPtr;
-use bevy::reflect::func::args::Ownership;
 use bevy::reflect::func::{
     ArgList, ArgValue, DynamicFunction, FunctionError, FunctionInfo, FunctionRegistry, IntoReturn,
     ReflectFn, Return, TypedFunction,
 };
 use bevy::reflect::{impl_reflect, ReflectFromPtr, Typed};
 use piccolo::{
-    Callback, CallbackReturn, Closure, Context, Executor, IntoValue, Lua, Table, TypeError,
-    UserData, Value, Variadic,
+    Callback, CallbackReturn, Closure, Context, Executor, ExternError, FromMultiValue,
+    IntoMultiValue, IntoValue, Lua, StashedFunction, Table, TypeError, UserData, Value, Variadic,
 };
 use send_wrapper::SendWrapper;
 use std::any::{Any, TypeId};
@@ -32,7 +44,10 @@ use std::io::Cursor;
 use std::ops::DerefMut;
 use std::ptr::NonNull;
 use std::rc::Rc;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub use blua_macros::methods;
 
 pub struct LuaPlugin;
 
@@ -61,12 +76,50 @@ impl Plugin for LuaPlugin {
             .init_asset::<LuaScript>();
         app.add_systems(Startup, insert_lua_vm);
         app.add_systems(Update, lua_asset_handling);
-        app.add_systems(Update, run_every_tick);
+        app.add_systems(First, install_lua_systems);
+        app.add_systems(Last, forget_despawned_entities);
         app.register_object_function::<CommandQueueWrapper>(
             CommandQueueWrapper::spawn
                 .into_function()
                 .with_name("spawn"),
         );
+        app.register_object_function::<CommandQueueWrapper>(
+            CommandQueueWrapper::entity
+                .into_function()
+                .with_name("entity"),
+        );
+        app.register_object_function::<LuaEntityCommands>(
+            LuaEntityCommands::insert
+                .into_function()
+                .with_name("insert"),
+        );
+        app.register_object_function::<LuaEntityCommands>(
+            LuaEntityCommands::remove
+                .into_function()
+                .with_name("remove"),
+        );
+        app.register_object_function::<LuaEntityCommands>(
+            LuaEntityCommands::despawn
+                .into_function()
+                .with_name("despawn"),
+        );
+        app.register_object_function::<LuaEntityCommands>(
+            LuaEntityCommands::despawn_recursive
+                .into_function()
+                .with_name("despawn_recursive"),
+        );
+        app.register_object_function::<LuaEntityCommands>(
+            LuaEntityCommands::add_child
+                .into_function()
+                .with_name("add_child"),
+        );
+        app.register_object_function::<LuaEntityCommands>(
+            LuaEntityCommands::set_parent
+                .into_function()
+                .with_name("set_parent"),
+        );
+        app.register_type::<LuaCallback>();
+        register_glam_operators(app);
     }
 }
 
@@ -81,135 +134,113 @@ pub trait AppExtensionFunctionRegisterTrait {
     );
 }
 
+pub trait AppExtensionSystemSetTrait {
+    /// Lets lua systems order against `set` with `after`, `before` and `in_set` using `name`.
+    fn expose_system_set_to_lua(&mut self, name: &str, set: impl SystemSet) -> &mut Self;
+}
+
+impl AppExtensionSystemSetTrait for App {
+    fn expose_system_set_to_lua(&mut self, name: &str, set: impl SystemSet) -> &mut Self {
+        self.init_resource::<LuaSystemSets>();
+        self.world_mut()
+            .resource_mut::<LuaSystemSets>()
+            .insert(name.to_string(), set.intern());
+        self
+    }
+}
+
+/// Calls the first of `overloads` the lua arguments can be converted for.
 pub fn lua_wrapped_dynamic_function_call<'gc>(
     ctx: Context<'gc>,
-    function: DynamicFunction<'static>,
+    overloads: Vec<DynamicFunction<'static>>,
     object_function_registry: Rc<RefCell<ObjectFunctionRegistry>>,
 ) -> Value<'gc> {
     Callback::from_fn(&ctx, move |context, _fuel, mut stack| {
-        let mut args_list = ArgList::new();
-        use bevy::prelude::Function;
         let args_uwu: Variadic<Vec<Value>> = stack.consume(context)?;
-        let args = function.info().args();
-        for (v, arg_info) in args_uwu.into_iter().zip(args) {
-            match v {
-                Value::Nil => {
-                    args_list = args_list.push_owned(());
-                }
-                Value::Boolean(bool) => {
-                    args_list = args_list.push_owned(bool);
-                }
-                Value::Integer(int) => {
-                    args_list = args_list.push_owned(int);
-                }
-                Value::Number(float) => {
-                    args_list = args_list.push_owned(float);
-                }
-                Value::String(lua_string) => {
-                    args_list = args_list.push_owned(lua_string.to_str().unwrap().to_string())
-                }
-                Value::Table(table) => {
-                    args_list = args_list.push_owned(unsafe { TableReflectWrapper::new(table) });
-                }
-                Value::Function(lua_function) => {
-                    todo!()
-                }
-                Value::UserData(user_data) => {
-                    if let Ok(reflect) = user_data.downcast_static::<ReflectPtr>() {
-                        match arg_info.ownership() {
-                            Ownership::Ref => {
-                                args_list = args_list
-                                    .push_ref(reflect.get_field_value_ref().as_partial_reflect())
-                            }
-                            Ownership::Mut => {
-                                args_list = args_list.push_mut(
-                                    reflect.get_field_value_mut().as_partial_reflect_mut(),
-                                )
-                            }
-                            Ownership::Owned => todo!(),
-                        }
-                    } else {
-                        todo!()
-                    }
-                }
-                Value::Thread(_) => {}
-            }
-        }
-        let ret = function.call(args_list).unwrap();
-        match ret {
-            Return::Owned(mut owned) => {
-                if let Some(awa) = owned.try_as_reflect().unwrap().downcast_ref::<f32>() {
-                    stack.push_front(Value::Number(*awa as f64))
-                }
-                if let Some(awa) = owned.try_as_reflect().unwrap().downcast_ref::<f64>() {
-                    stack.push_front(Value::Number(*awa))
-                }
-
-                if let Some(awa) = owned.try_as_reflect().unwrap().downcast_ref::<i32>() {
-                    stack.push_front(Value::Integer(*awa as i64))
-                }
-                if let Some(awa) = owned.try_as_reflect().unwrap().downcast_ref::<i64>() {
-                    stack.push_front(Value::Integer(*awa))
-                }
-                let reflect_ptr = ReflectPtr::new_boxed(
-                    owned.try_into_reflect().unwrap(),
-                    Rc::new(RefCell::new(PtrState::Valid)),
-                    object_function_registry.clone(),
-                );
-                stack.push_front(reflect_ptr.into_value(&context));
-            }
-            Return::Ref(_) => {
-                println!("todo return &");
-            }
-            Return::Mut(_) => {
-                println!("todo return &mut");
-            }
+        let values = call_overloads(context, &overloads, args_uwu.0, &object_function_registry)?;
+        for value in values {
+            stack.push_back(value);
         }
         Ok(CallbackReturn::Return)
     })
     .into_value(ctx)
 }
+
+/// The body of `lua_wrapped_dynamic_function_call`, for calling from inside other callbacks.
+pub fn call_overloads<'gc>(
+    context: Context<'gc>,
+    overloads: &[DynamicFunction<'static>],
+    args_uwu: Vec<Value<'gc>>,
+    object_function_registry: &Rc<RefCell<ObjectFunctionRegistry>>,
+) -> Result<Vec<Value<'gc>>, piccolo::Error<'gc>> {
+    use bevy::prelude::Function;
+    let mut errors = vec![];
+    let mut found = None;
+    for function in overloads {
+        match lua_to_args(context, args_uwu.clone(), function.info()) {
+            Ok(args) => {
+                found = Some((function, args));
+                break;
+            }
+            Err(err) => errors.push(err.to_string()),
+        }
+    }
+    let Some((function, mut args)) = found else {
+        let message = match errors.as_slice() {
+            [error] => error.clone(),
+            errors => format!("no overload fits:\n{}", errors.join("\n")),
+        };
+        return Err(anyhow::anyhow!(message).into());
+    };
+    let info = function.info();
+    for arg in &mut args {
+        arg.take_moved()
+            .map_err(|err| anyhow::anyhow!("{}: {err}", signature(info)))?;
+    }
+    let borrowed_ptr_state = borrowed_ptr_state(&args, info);
+    let mut args_list = ArgList::new();
+    for (arg, arg_info) in args.iter_mut().zip(info.args()) {
+        args_list = arg
+            .push(args_list, arg_info.ownership())
+            .map_err(|err| anyhow::anyhow!("{}: {err}", signature(info)))?;
+    }
+    let ret = function
+        .call(args_list)
+        .map_err(|err| anyhow::anyhow!("{}: {err}", signature(info)))?;
+    return_to_lua(context, ret, borrowed_ptr_state, object_function_registry)
+}
+
 impl AppExtensionFunctionRegisterTrait for App {
     fn register_object_function<T: Reflect>(&mut self, function: DynamicFunction<'static>) {
         self.init_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>();
-        let mut object_function_registry = self
+        let object_function_registry = self
             .world_mut()
             .get_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>()
             .unwrap();
-        if !object_function_registry
-            .borrow()
-            .contains_key(&TypeId::of::<T>())
-        {
-            object_function_registry
-                .borrow_mut()
-                .insert(TypeId::of::<T>(), FunctionRegistry::default());
-        }
-        let mut awa = object_function_registry.borrow_mut();
-        let function_registry = awa.get_mut(&TypeId::of::<T>()).unwrap();
-        function_registry.register(function).unwrap();
+        object_function_registry
+            .borrow_mut()
+            .register(TypeId::of::<T>(), function);
     }
     fn register_non_self_object_function<T: Reflect + Typed>(
         &mut self,
         function: DynamicFunction<'static>,
     ) {
         self.init_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>();
-        let mut object_function_registry = self
+        let object_function_registry = self
             .world_mut()
             .get_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>()
             .unwrap()
             .clone();
-        let mut ofr1 = object_function_registry.clone();
-        if !object_function_registry
+        let ofr1 = object_function_registry.clone();
+        let name = function.name().unwrap().to_string();
+        object_function_registry
+            .borrow_mut()
+            .register(TypeId::of::<T>(), function);
+        // the lua function has to dispatch to every overload registered so far
+        let overloads = object_function_registry
             .borrow()
-            .contains_key(&TypeId::of::<T>())
-        {
-            object_function_registry
-                .borrow_mut()
-                .insert(TypeId::of::<T>(), FunctionRegistry::default());
-        }
-        let mut awa = object_function_registry.borrow_mut();
-        let function_registry = awa.get_mut(&TypeId::of::<T>()).unwrap();
-        function_registry.register(function.clone()).unwrap();
+            .overloads(TypeId::of::<T>(), &name)
+            .unwrap();
 
         let world = self.world_mut();
 
@@ -226,13 +257,9 @@ impl AppExtensionFunctionRegisterTrait for App {
             .try_enter(move |ctx| {
                 let mut lua_table = ctx.globals();
                 let len = things.len();
-                let f = function.clone();
                 for (i, item) in things.into_iter().enumerate() {
                     if i + 1 == len {
-                        let name = function.name().unwrap().to_string();
-
-                        let f = f.clone();
-                        let function = lua_wrapped_dynamic_function_call(ctx, f, ofr1);
+                        let function = lua_wrapped_dynamic_function_call(ctx, overloads, ofr1);
 
                         let t = match lua_table.get(ctx, item).unwrap() {
                             Value::Nil => {
@@ -286,8 +313,8 @@ pub fn lua_asset_handling(world: &mut World) {
         for (new_script_bytes, new_script_path) in
             lua_asset_communicator.lua_script_bytes_rx.try_iter()
         {
-            let mut systems_vec = Rc::new(RefCell::new(Some(Vec::new())));
-            let exec = lua
+            let mut systems_vec = Rc::new(RefCell::new(Some(HashMap::new())));
+            let result = lua
                 .try_enter(|ctx| {
                     let user_data = UserData::new_static(&ctx, systems_vec.clone());
                     ctx.set_global("__systems_vec", user_data);
@@ -299,13 +326,16 @@ pub fn lua_asset_handling(world: &mut World) {
                     )?;
                     Ok(ctx.stash(Executor::start(ctx, closure.into(), lua_app_value)))
                 })
-                .unwrap();
-            lua.execute::<()>(&exec).unwrap();
+                .and_then(|exec| lua.execute::<()>(&exec));
+            let lua_script = match result {
+                Ok(()) => Ok(LuaScript {
+                    systems: SendWrapper::new(systems_vec.take().unwrap()),
+                }),
+                Err(err) => Err(anyhow::anyhow!("{new_script_path}: {err}")),
+            };
             lua_asset_communicator
                 .lua_script_tx
-                .send(LuaScript {
-                    systems: SendWrapper::new(systems_vec.take().unwrap()),
-                })
+                .send(lua_script)
                 .unwrap();
         }
         lua.try_enter(|ctx| {
@@ -319,8 +349,296 @@ pub fn lua_asset_handling(world: &mut World) {
     });
 }
 
+/// One value of a query row.
+#[derive(Clone)]
+pub enum QueryItem {
+    Entity(Entity),
+    Component(ReflectPtr),
+    /// An optional component the entity doesn't have. Lua's `for` stops at a leading `nil`,
+    /// so queries starting with an optional term are rejected when they're parsed.
+    Missing,
+}
+
+impl QueryItem {
+    fn into_value<'gc>(self, ctx: &Context<'gc>) -> Value<'gc> {
+        match self {
+            QueryItem::Entity(entity) => LuaEntity(entity).into_value(ctx),
+            QueryItem::Component(reflect_ptr) => reflect_ptr.into_value(ctx),
+            QueryItem::Missing => Value::Nil,
+        }
+    }
+}
+
+/// A query parameter of a lua system. Rows are read through the system's `Query` when a
+/// method is called, so `get` is a lookup instead of a walk over every matching entity.
+pub struct QueryRows {
+    /// Only dereferenced while `ptr_state` is valid, i.e. while the system is running.
+    query: *mut Query<'static, 'static, FilteredEntityMut<'static>>,
+    lua_query: LuaQuery,
+    app_registry: AppTypeRegistry,
+    object_function_registry: Rc<RefCell<ObjectFunctionRegistry>>,
+    last_run: Tick,
+    this_run: Tick,
+    /// Whether any term hands out mutable access, in which case `get_many` can't alias.
+    pub has_mut: bool,
+    pub ptr_state: Rc<RefCell<PtrState>>,
+}
+
+impl QueryRows {
+    pub fn new(
+        query: &mut Query<FilteredEntityMut>,
+        lua_query: LuaQuery,
+        app_registry: AppTypeRegistry,
+        object_function_registry: Rc<RefCell<ObjectFunctionRegistry>>,
+        change_tick: &SystemChangeTick,
+        ptr_state: Rc<RefCell<PtrState>>,
+    ) -> Self {
+        let has_mut = lua_query.terms.iter().any(|term| {
+            matches!(
+                term,
+                QueryTerm::Component(ComponentType::Mut(_) | ComponentType::OptMut(_))
+            )
+        });
+        Self {
+            query: query as *mut Query<FilteredEntityMut>
+                as *mut Query<'static, 'static, FilteredEntityMut<'static>>,
+            lua_query,
+            app_registry,
+            object_function_registry,
+            last_run: change_tick.last_run(),
+            this_run: change_tick.this_run(),
+            has_mut,
+            ptr_state,
+        }
+    }
+
+    fn with_query<R>(
+        &self,
+        f: impl FnOnce(&mut Query<'static, 'static, FilteredEntityMut<'static>>) -> R,
+    ) -> anyhow::Result<R> {
+        if &*self.ptr_state.borrow() == &PtrState::Invalid {
+            anyhow::bail!("query used outside of the system it was passed to")
+        }
+        Ok(f(unsafe { &mut *self.query }))
+    }
+
+    fn matches(&self, entity: &FilteredEntityMut) -> bool {
+        self.lua_query
+            .matches_ticks(entity, self.last_run, self.this_run)
+    }
+
+    fn row(&self, mut entity: FilteredEntityMut) -> Vec<QueryItem> {
+        let app_registry = self.app_registry.read();
+        let mut values = vec![];
+        for term in self.lua_query.terms.iter() {
+            let component_type = match term {
+                QueryTerm::Entity => {
+                    values.push(QueryItem::Entity(entity.id()));
+                    continue;
+                }
+                QueryTerm::Component(component_type) => component_type,
+            };
+            let (_, type_id) = component_type.ids();
+            let reflect_from_ptr = app_registry
+                .get_type_data::<ReflectFromPtr>(type_id)
+                .unwrap();
+            match component_type {
+                ComponentType::Ref((component_id, _))
+                | ComponentType::OptRef((component_id, _)) => {
+                    let Some(x) = entity.get_by_id(*component_id) else {
+                        values.push(QueryItem::Missing);
+                        continue;
+                    };
+                    let value = unsafe { reflect_from_ptr.as_reflect(x) };
+                    values.push(QueryItem::Component(ReflectPtr::new_ref(
+                        value,
+                        self.ptr_state.clone(),
+                        self.object_function_registry.clone(),
+                    )));
+                }
+                ComponentType::Mut((component_id, _))
+                | ComponentType::OptMut((component_id, _)) => {
+                    let Some(x) = entity.get_mut_by_id(*component_id) else {
+                        values.push(QueryItem::Missing);
+                        continue;
+                    };
+                    values.push(QueryItem::Component(unsafe {
+                        ReflectPtr::new_tracked(
+                            x,
+                            reflect_from_ptr,
+                            self.ptr_state.clone(),
+                            self.object_function_registry.clone(),
+                        )
+                    }));
+                }
+            }
+        }
+        values
+    }
+
+    /// The row of `entity`, if it matches the query.
+    pub fn get(&self, entity: Entity) -> anyhow::Result<Option<Vec<QueryItem>>> {
+        self.with_query(|query| {
+            query
+                .get_mut(entity)
+                .ok()
+                .filter(|a| self.matches(a))
+                .map(|a| self.row(a))
+        })
+    }
+
+    /// Every matching row, read when this is called.
+    pub fn rows(&self) -> anyhow::Result<Vec<Vec<QueryItem>>> {
+        self.with_query(|query| {
+            query
+                .iter_mut()
+                .filter(|a| self.matches(a))
+                .map(|a| self.row(a))
+                .collect()
+        })
+    }
+
+    pub fn count(&self) -> anyhow::Result<usize> {
+        self.with_query(|query| query.iter_mut().filter(|a| self.matches(a)).count())
+    }
+
+    /// The table lua systems get for a query parameter.
+    pub fn into_table<'gc>(self, ctx: Context<'gc>) -> Table<'gc> {
+        let rows = Rc::new(self);
+        let t = Table::new(&ctx);
+
+        let iter_rows = rows.clone();
+        t.set(
+            ctx,
+            "iter",
+            Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+                let iterator_state = UserData::new_static(
+                    &ctx,
+                    Mutex::new(IteratorState {
+                        components: iter_rows.rows()?,
+                        ptr_state: iter_rows.ptr_state.clone(),
+                    }),
+                );
+                stack.replace(
+                    ctx,
+                    (
+                        IteratorState::iterator_fn(&ctx),
+                        Value::UserData(iterator_state),
+                    ),
+                );
+                Ok(CallbackReturn::Return)
+            }),
+        )
+        .unwrap();
+
+        let get_rows = rows.clone();
+        t.set(
+            ctx,
+            "get",
+            Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+                let (_, entity): (Table, &LuaEntity) = stack.consume(ctx)?;
+                if let Some(row) = get_rows.get(entity.0)? {
+                    for value in row {
+                        stack.push_back(value.into_value(&ctx));
+                    }
+                }
+                Ok(CallbackReturn::Return)
+            }),
+        )
+        .unwrap();
+
+        let single_rows = rows.clone();
+        t.set(
+            ctx,
+            "single",
+            Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+                stack.clear();
+                let row = single_rows.with_query(|query| {
+                    let mut matching = query.iter_mut().filter(|a| single_rows.matches(a));
+                    let Some(first) = matching.next() else {
+                        anyhow::bail!("single: the query matched no entities");
+                    };
+                    let others = matching.count();
+                    if others > 0 {
+                        anyhow::bail!(
+                            "single: the query matched {} entities instead of one",
+                            others + 1
+                        );
+                    }
+                    Ok(single_rows.row(first))
+                })??;
+                for value in row {
+                    stack.push_back(value.into_value(&ctx));
+                }
+                Ok(CallbackReturn::Return)
+            }),
+        )
+        .unwrap();
+
+        let get_many_rows = rows.clone();
+        t.set(
+            ctx,
+            "get_many",
+            Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+                let (_, entities): (Table, Table) = stack.consume(ctx)?;
+                let mut seen = Vec::new();
+                for i in 1..=entities.length() {
+                    let entity = entities.get::<_, &LuaEntity>(ctx, i)?.0;
+                    if get_many_rows.has_mut && seen.contains(&entity) {
+                        return Err(anyhow::anyhow!(
+                            "get_many: {entity} was passed twice to a query with mutable terms"
+                        )
+                        .into());
+                    }
+                    seen.push(entity);
+                    let Some(row) = get_many_rows.get(entity)? else {
+                        return Err(
+                            anyhow::anyhow!("get_many: {entity} doesn't match the query").into(),
+                        );
+                    };
+                    // every entity gets its own row table, since a row can be several values
+                    let row_table = Table::new(&ctx);
+                    for (j, value) in row.into_iter().enumerate() {
+                        row_table
+                            .set(ctx, j as i64 + 1, value.into_value(&ctx))
+                            .unwrap();
+                    }
+                    stack.push_back(row_table.into_value(ctx));
+                }
+                Ok(CallbackReturn::Return)
+            }),
+        )
+        .unwrap();
+
+        let count_rows = rows.clone();
+        t.set(
+            ctx,
+            "count",
+            Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+                stack.replace(ctx, count_rows.count()? as i64);
+                Ok(CallbackReturn::Return)
+            }),
+        )
+        .unwrap();
+
+        t.set(
+            ctx,
+            "is_empty",
+            Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+                let is_empty =
+                    rows.with_query(|query| !query.iter_mut().any(|a| rows.matches(&a)))?;
+                stack.replace(ctx, is_empty);
+                Ok(CallbackReturn::Return)
+            }),
+        )
+        .unwrap();
+
+        t
+    }
+}
+
 pub struct IteratorState {
-    pub components: Vec<Vec<ReflectPtr>>,
+    pub components: Vec<Vec<QueryItem>>,
     pub ptr_state: Rc<RefCell<PtrState>>,
 }
 
@@ -347,224 +665,213 @@ impl IteratorState {
     }
 }
 
-pub fn run_every_tick(world: &mut World) {
-    let mut lua = world.remove_non_send_resource::<LuaVm>().unwrap();
+/// Lets `spawn` reserve an entity id before its command is applied.
+/// Only set while the lua system owning the queue is running.
+#[derive(Clone, Copy)]
+struct EntitiesPtr(*const Entities);
 
-    let mut lua_scripts = world.remove_resource::<Assets<LuaScript>>().unwrap();
+unsafe impl Send for EntitiesPtr {}
+unsafe impl Sync for EntitiesPtr {}
 
-    let app_registry = world.get_resource::<AppTypeRegistry>().unwrap().clone();
-    world.init_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>();
-    let object_function_registry = world
-        .get_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>()
-        .unwrap()
-        .clone();
-    for (_, awa) in lua_scripts.iter_mut() {
-        let mut command_queue = CommandQueueWrapper {
-            commands: Default::default(),
-        };
-        for awa in awa.systems.iter_mut() {
-            let stashed_function = &awa.lua_func;
-            let mut ptr_states = vec![];
-            let ofr1 = object_function_registry.clone();
-            let (exec) = lua
-                .try_enter(|ctx| {
-                    let func = ctx.fetch(stashed_function);
-                    let mut things = vec![];
-
-                    for system_parameter in &mut awa.system_parameters {
-                        let ptr_state = Rc::new(RefCell::new(PtrState::Valid));
-                        let ptr_state2 = ptr_state.clone();
-                        match system_parameter {
-                            SystemParameter::Query((query, component_infos)) => {
-                                let items = query.iter_mut(world).collect::<Vec<_>>();
-                                let items = items
-                                    .into_iter()
-                                    .map(|mut a| {
-                                        let mut values = vec![];
-                                        //a.components();
-                                        for component_type in component_infos.iter() {
-                                            match component_type {
-                                                ComponentType::Ref((component_id, type_id)) => {
-                                                    let mut x = a.get_by_id(*component_id).unwrap();
-                                                    let app_registry = app_registry.read();
-                                                    let reflect_data =
-                                                        app_registry.get(*type_id).unwrap();
-                                                    let reflect_from_ptr = reflect_data
-                                                        .data::<ReflectFromPtr>()
-                                                        .unwrap();
-                                                    let value =
-                                                        unsafe { reflect_from_ptr.as_reflect(x) };
-                                                    values.push(ReflectPtr::new_ref(
-                                                        value,
-                                                        ptr_state2.clone(),
-                                                        ofr1.clone(),
-                                                    ));
-                                                }
-                                                ComponentType::Mut((component_id, type_id)) => {
-                                                    let mut x =
-                                                        a.get_mut_by_id(*component_id).unwrap();
-                                                    let app_registry = app_registry.read();
-                                                    let reflect_data =
-                                                        app_registry.get(*type_id).unwrap();
-                                                    let reflect_from_ptr = reflect_data
-                                                        .data::<ReflectFromPtr>()
-                                                        .unwrap();
-                                                    let value = unsafe {
-                                                        reflect_from_ptr.as_reflect_mut(x.as_mut())
-                                                    };
-                                                    values.push(ReflectPtr::new_mut(
-                                                        value,
-                                                        ptr_state2.clone(),
-                                                        ofr1.clone(),
-                                                    ));
-                                                }
-                                            }
-                                        }
-                                        values
-                                    })
-                                    .collect::<Vec<_>>();
-                                let iterator_state = ctx.stash(UserData::new_static(
-                                    &ctx,
-                                    Mutex::new(IteratorState {
-                                        components: items,
-                                        ptr_state: ptr_state.clone(),
-                                    }),
-                                ));
-                                ptr_states.push(ptr_state);
-                                let t = Table::new(&ctx);
-                                t.set(
-                                    ctx,
-                                    "iter",
-                                    Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
-                                        let iterator_state =
-                                            ctx.fetch(&iterator_state).into_value(ctx);
-                                        *iterator_state
-                                            .as_static_user_data::<Mutex<IteratorState>>()
-                                            .unwrap()
-                                            .lock()
-                                            .unwrap()
-                                            .ptr_state
-                                            .borrow_mut() = PtrState::Valid;
-                                        stack.replace(
-                                            ctx,
-                                            (IteratorState::iterator_fn(&ctx), iterator_state),
-                                        );
-
-                                        Ok(CallbackReturn::Return)
-                                    }),
-                                )
-                                .unwrap();
-                                things.push(t.into_value(ctx));
-                            }
-                            SystemParameter::CommandQueue => {
-                                let reflect_mut = ReflectPtr::new_mut(
-                                    &mut command_queue,
-                                    ptr_state2.clone(),
-                                    ofr1.clone(),
-                                );
-                                things.push(reflect_mut.into_value(&ctx));
-                                ptr_states.push(ptr_state);
-                            }
-                            SystemParameter::Resource(resource_component_type) => {
-                                match resource_component_type {
-                                    ComponentType::Ref((component_id, type_id)) => {
-                                        let mut x =
-                                            world.get_resource_by_id(*component_id).unwrap();
-                                        let app_registry = app_registry.read();
-                                        let reflect_data = app_registry.get(*type_id).unwrap();
-                                        let reflect_from_ptr =
-                                            reflect_data.data::<ReflectFromPtr>().unwrap();
-                                        let value = unsafe { reflect_from_ptr.as_reflect(x) };
-                                        things.push(
-                                            ReflectPtr::new_ref(
-                                                value,
-                                                ptr_state2.clone(),
-                                                ofr1.clone(),
-                                            )
-                                            .into_value(&ctx),
-                                        );
-                                    }
-                                    ComponentType::Mut((component_id, type_id)) => {
-                                        let mut x =
-                                            world.get_resource_mut_by_id(*component_id).unwrap();
-                                        let app_registry = app_registry.read();
-                                        let reflect_data = app_registry.get(*type_id).unwrap();
-                                        let reflect_from_ptr =
-                                            reflect_data.data::<ReflectFromPtr>().unwrap();
-                                        let value =
-                                            unsafe { reflect_from_ptr.as_reflect_mut(x.as_mut()) };
-                                        things.push(
-                                            ReflectPtr::new_mut(
-                                                value,
-                                                ptr_state2.clone(),
-                                                ofr1.clone(),
-                                            )
-                                            .into_value(&ctx),
-                                        );
-                                    }
-                                }
-                            }
-                        }
-                    }
+#[derive(Reflect, Default, Deref, DerefMut)]
+pub struct CommandQueueWrapper {
+    #[reflect(ignore)]
+    #[deref]
+    pub commands: CommandQueue,
+    #[reflect(ignore)]
+    entities: Option<EntitiesPtr>,
+    /// Shared with every `LuaEntityCommands` made from this queue, they can outlive it in lua.
+    #[reflect(ignore)]
+    running: Arc<AtomicBool>,
+}
 
-                    Ok(ctx.stash(Executor::start(ctx, func, Variadic(things))))
-                })
-                .unwrap();
-            if let Err(err) = lua.execute::<()>(&exec) {
-                println!("error running lua script didn't work: {err}");
-            }
-            for ptr_state in ptr_states.iter() {
-                *ptr_state.borrow_mut() = PtrState::Invalid;
-            }
-            command_queue.commands.apply(world);
+impl SystemBuffer for CommandQueueWrapper {
+    fn apply(&mut self, _system_meta: &SystemMeta, world: &mut World) {
+        self.commands.apply(world);
+    }
+}
+
+impl CommandQueueWrapper {
+    /// `entities` has to outlive every lua call made until this is set back to `None`.
+    pub(crate) fn set_entities(&mut self, entities: Option<&Entities>) {
+        self.entities = entities.map(|entities| EntitiesPtr(entities as *const Entities));
+        self.running.store(entities.is_some(), Ordering::Relaxed);
+    }
+
+    pub fn spawn(&mut self, table: TableReflectWrapper) -> Result<Entity, String> {
+        let entities = self.entities.ok_or(NOT_RUNNING)?;
+        let components = take_components(table)?;
+        let entity = unsafe { &*entities.0 }.reserve_entity();
+        self.entity(entity).push(insert_components(components))?;
+        Ok(entity)
+    }
+
+    pub fn entity(&mut self, entity: Entity) -> LuaEntityCommands {
+        LuaEntityCommands {
+            entity,
+            commands: Some(CommandQueuePtr {
+                running: self.running.clone(),
+                queue: self,
+            }),
         }
     }
+}
 
-    world.insert_resource(lua_scripts);
-    world.insert_non_send_resource(lua);
+const NOT_RUNNING: &str =
+    "lua commands can only be used while the system they came from is running";
+
+struct CommandQueuePtr {
+    queue: *mut CommandQueueWrapper,
+    running: Arc<AtomicBool>,
 }
 
-#[derive(Reflect, Deref, DerefMut)]
-pub struct CommandQueueWrapper {
+unsafe impl Send for CommandQueuePtr {}
+unsafe impl Sync for CommandQueuePtr {}
+
+/// What `commands:entity(e)` returns, queues commands for a single entity.
+#[derive(Reflect)]
+pub struct LuaEntityCommands {
+    entity: Entity,
     #[reflect(ignore)]
-    pub commands: CommandQueue,
+    commands: Option<CommandQueuePtr>,
 }
 
-impl CommandQueueWrapper {
-    pub fn spawn(&mut self, table: TableReflectWrapper) {
-        self.push(move |world: &mut World| {
-            let table = table.table.unwrap().take();
-            for (_key, value) in table {
-                let Ok(value) = value.as_static_user_data::<ReflectPtr>() else {
-                    println!("passed non reflect to spawn function");
-                    continue;
-                };
-                let type_id = unsafe { &*value.get_data() }
-                    .get_represented_type_info()
-                    .unwrap()
-                    .type_id();
-                match &value.data {
-                    ReflectType::Boxed(boxed) => {
-                        let thing_to_add = boxed.borrow_mut().take().unwrap();
-                        let component_id: ComponentId = world.components().get_id(type_id).unwrap();
-                        let mut e: EntityWorldMut = world.spawn_empty();
-                        let data_ptr = Box::into_raw(thing_to_add) as *mut u8;
-                        unsafe {
-                            e.insert_by_id(
-                                component_id,
-                                OwningPtr::new(NonNull::new(data_ptr).unwrap()),
-                            )
-                        };
-                    }
-                    _ => {
-                        eprintln!("error non boxed component in spawn function");
-                        continue;
-                    }
-                }
+impl LuaEntityCommands {
+    fn push(
+        &mut self,
+        command: impl FnOnce(EntityWorldMut) + Send + 'static,
+    ) -> Result<(), String> {
+        let commands = self.commands.as_ref().ok_or(NOT_RUNNING)?;
+        if !commands.running.load(Ordering::Relaxed) {
+            return Err(NOT_RUNNING.to_string());
+        }
+        let commands = unsafe { &mut *commands.queue };
+        let entity = self.entity;
+        commands.push(move |world: &mut World| {
+            if world.entities().contains(entity) {
+                command(world.entity_mut(entity));
+            } else {
+                warn!("lua command on {entity}, which doesn't exist");
             }
         });
+        Ok(())
+    }
+
+    pub fn insert(&mut self, table: TableReflectWrapper) -> Result<(), String> {
+        if !self.is_running() {
+            return Err(NOT_RUNNING.to_string());
+        }
+        let components = take_components(table)?;
+        self.push(insert_components(components))
+    }
+
+    /// Takes the namespace table of the component, like `remove(Velocity)`.
+    pub fn remove(&mut self, component: TableReflectWrapper) -> Result<(), String> {
+        let namespace = unsafe { component.take() };
+        let component_id = namespace.into_iter().find_map(|(key, value)| {
+            let Value::String(key) = key else {
+                return None;
+            };
+            if key.as_bytes() != b"ref" {
+                return None;
+            }
+            Some(value.as_static_user_data::<ComponentType>().ok()?.ids().0)
+        });
+        let Some(component_id) = component_id else {
+            return Err("remove takes a component's namespace table, like `Velocity`".into());
+        };
+        self.push(move |mut e| {
+            e.remove_by_id(component_id);
+        })
+    }
+
+    pub fn despawn(&mut self) -> Result<(), String> {
+        self.push(|e| e.despawn())
+    }
+
+    pub fn despawn_recursive(&mut self) -> Result<(), String> {
+        self.push(|e| e.despawn_recursive())
+    }
+
+    pub fn add_child(&mut self, child: Entity) -> Result<(), String> {
+        self.push(move |mut e| {
+            e.add_child(child);
+        })
+    }
+
+    pub fn set_parent(&mut self, parent: Entity) -> Result<(), String> {
+        self.push(move |mut e| {
+            e.set_parent(parent);
+        })
+    }
+
+    fn is_running(&self) -> bool {
+        self.commands
+            .as_ref()
+            .is_some_and(|commands| commands.running.load(Ordering::Relaxed))
     }
 }
+
+/// Moves the boxed components of a table like `{ Transform{}, Player{} }` out of lua. All of
+/// them are checked before any is moved.
+fn take_components(table: TableReflectWrapper) -> Result<Vec<Box<dyn Reflect>>, String> {
+    let table = unsafe { table.take() };
+    let mut components = vec![];
+    for (_key, value) in table {
+        let Ok(component) = value.as_static_user_data::<ReflectPtr>() else {
+            return Err(format!(
+                "expected components made like `Transform{{}}`, got a lua {}",
+                value.type_name()
+            ));
+        };
+        let type_path = component
+            .try_field_value_ref()
+            .map_err(|err| err.to_string())?
+            .reflect_type_path();
+        if !matches!(component.data, ReflectType::Boxed(_)) || !component.is_root() {
+            return Err(format!(
+                "{type_path} is part of something else, make a new one to insert it"
+            ));
+        }
+        components.push(component);
+    }
+    components
+        .into_iter()
+        .map(|component| {
+            component
+                .take()
+                .and_then(|value| {
+                    value.try_into_reflect().map_err(|value| {
+                        anyhow::anyhow!("{} isn't a full Reflect type", value.reflect_type_path())
+                    })
+                })
+                .map_err(|err| err.to_string())
+        })
+        .collect()
+}
+
+fn insert_components(components: Vec<Box<dyn Reflect>>) -> impl FnOnce(EntityWorldMut) + Send {
+    move |mut e| {
+        for component in components {
+            let type_info = component.reflect_type_info();
+            let Some(component_id) = e.world().components().get_id(type_info.type_id()) else {
+                warn!(
+                    "tried to insert {}, which isn't a component",
+                    type_info.type_path()
+                );
+                continue;
+            };
+            let data_ptr = Box::into_raw(component) as *mut u8;
+            unsafe {
+                e.insert_by_id(
+                    component_id,
+                    OwningPtr::new(NonNull::new(data_ptr).unwrap()),
+                )
+            };
+        }
+    }
+}
+
 #[derive(Deref, DerefMut)]
 pub struct LuaVm {
     lua: Lua,
@@ -574,3 +881,182 @@ impl Default for LuaVm {
         Self { lua: Lua::full() }
     }
 }
+
+/// A lua function passed to a rust function, e.g. `timer:on_finished(function() end)`.
+/// The vm is busy while it runs the lua code that passed the callback, so it has to be called
+/// later, like from a system taking `NonSendMut<LuaVm>`.
+/// Reflected as an opaque value so copies made through reflection keep the function.
+#[derive(Reflect, Default)]
+#[reflect(opaque, Default)]
+pub struct LuaCallback {
+    function: Option<SendWrapper<StashedFunction>>,
+}
+
+impl Clone for LuaCallback {
+    fn clone(&self) -> Self {
+        Self {
+            function: self
+                .function
+                .as_ref()
+                .map(|function| SendWrapper::new((**function).clone())),
+        }
+    }
+}
+
+impl LuaCallback {
+    pub fn new(function: StashedFunction) -> Self {
+        Self {
+            function: Some(SendWrapper::new(function)),
+        }
+    }
+
+    /// Runs the lua function with `args` to completion and returns what it returned.
+    pub fn call<A, R>(&self, lua: &mut LuaVm, args: A) -> Result<R, ExternError>
+    where
+        A: for<'gc> IntoMultiValue<'gc>,
+        R: for<'gc> FromMultiValue<'gc>,
+    {
+        let function = self.function.as_ref().ok_or_else(|| {
+            ExternError::from(anyhow::anyhow!("LuaCallback was made without a function"))
+        })?;
+        let exec = lua.try_enter(|ctx| {
+            let function = ctx.fetch(&**function);
+            Ok(ctx.stash(Executor::start(ctx, function, args)))
+        })?;
+        lua.execute::<R>(&exec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::asset::AssetPath;
+    use bevy::ecs::system::{ParamBuilder, QueryParamBuilder, SystemParamBuilder};
+
+    #[test]
+    fn entity_commands_only_while_running() {
+        let mut world = World::new();
+        let entity = world.spawn_empty().id();
+        let mut commands = CommandQueueWrapper::default();
+        assert!(commands.entity(entity).despawn().is_err());
+
+        commands.set_entities(Some(world.entities()));
+        let mut entity_commands = commands.entity(entity);
+        assert!(entity_commands.despawn().is_ok());
+        commands.set_entities(None);
+        // kept by lua past the end of its system
+        assert!(entity_commands.despawn().is_err());
+
+        commands.commands.apply(&mut world);
+        assert_eq!(world.iter_entities().count(), 0);
+    }
+
+    #[test]
+    fn empty_callbacks_error() {
+        let mut lua = LuaVm::default();
+        assert!(LuaCallback::default().call::<_, ()>(&mut lua, ()).is_err());
+    }
+
+    #[test]
+    fn reflected_callbacks_keep_their_function() {
+        let mut lua = LuaVm::default();
+        let function = lua
+            .try_enter(|ctx| {
+                let closure = Closure::load(ctx, None, &b"return ... + 1"[..])?;
+                Ok(ctx.stash(piccolo::Function::from(closure)))
+            })
+            .unwrap();
+        let callback = LuaCallback::new(function);
+        let copy = callback
+            .clone_value()
+            .try_take::<LuaCallback>()
+            .ok()
+            .unwrap();
+        assert_eq!(copy.call::<_, i64>(&mut lua, 1i64).ok(), Some(2));
+    }
+
+    #[test]
+    fn query_rows_are_looked_up_when_asked_for() {
+        let mut world = World::new();
+        let registry = AppTypeRegistry::default();
+        registry.write().register::<Transform>();
+        let transform = world.register_component::<Transform>();
+        let moved = world.spawn(Transform::from_xyz(1.0, 0.0, 0.0)).id();
+        world.spawn(Transform::default());
+        let missing = world.spawn_empty().id();
+
+        let lua_query = LuaQuery {
+            terms: vec![
+                QueryTerm::Entity,
+                QueryTerm::Component(ComponentType::Ref((transform, TypeId::of::<Transform>()))),
+            ],
+            ..default()
+        };
+        let build_query = lua_query.clone();
+        let system = (
+            QueryParamBuilder::new(move |builder: &mut QueryBuilder<FilteredEntityMut>| {
+                build_query.build(builder)
+            }),
+            ParamBuilder,
+        )
+            .build_state(&mut world)
+            .build_system(
+                move |mut query: Query<FilteredEntityMut>, change_tick: SystemChangeTick| {
+                    let ptr_state = Rc::new(RefCell::new(PtrState::Valid));
+                    let rows = QueryRows::new(
+                        &mut query,
+                        lua_query.clone(),
+                        registry.clone(),
+                        Rc::default(),
+                        &change_tick,
+                        ptr_state.clone(),
+                    );
+                    let row = rows.get(moved).unwrap().unwrap();
+                    let QueryItem::Component(moved_transform) = &row[1] else {
+                        panic!("expected a component");
+                    };
+                    let moved_transform = moved_transform.try_field_value_ref().unwrap();
+                    assert_eq!(
+                        moved_transform
+                            .downcast_ref::<Transform>()
+                            .unwrap()
+                            .translation
+                            .x,
+                        1.0
+                    );
+                    assert!(rows.get(missing).unwrap().is_none());
+                    assert_eq!(rows.count().unwrap(), 2);
+                    assert_eq!(rows.rows().unwrap().len(), 2);
+
+                    *ptr_state.borrow_mut() = PtrState::Invalid;
+                    assert!(rows.get(moved).is_err());
+                    assert!(rows.count().is_err());
+                },
+            );
+        let mut schedule = Schedule::default();
+        schedule.add_systems(system);
+        schedule.run(&mut world);
+    }
+
+    #[test]
+    fn scripts_that_error_fail_their_load() {
+        let mut world = World::new();
+        let loader = LuaAssetLoader::from_world(&mut world);
+        world.insert_non_send_resource(LuaVm::default());
+        for source in ["error('oops')", "this isn't lua", "local x = 1"] {
+            let path = AssetPath::from("test.lua");
+            loader
+                .lua_script_bytes_tx
+                .send((source.as_bytes().to_vec(), path))
+                .unwrap();
+        }
+        lua_asset_handling(&mut world);
+        let loaded = loader
+            .lua_script_rx
+            .try_iter()
+            .map(|script| script.is_ok())
+            .collect::<Vec<_>>();
+        assert_eq!(loaded, [false, false, true]);
+        assert!(world.get_non_send_resource::<LuaVm>().is_some());
+    }
+}
@@ -0,0 +1,275 @@
+use crate::reflect_stuff::ReflectPtr;
+use crate::return_stuff::primitive_to_lua;
+use crate::{call_overloads, AppExtensionFunctionRegisterTrait};
+use anyhow::anyhow;
+use bevy::prelude::*;
+use piccolo::{Callback, CallbackReturn, Context, Table, Value};
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+/// Lua operators, and the name of the function registered for a type that does them, like
+/// `<Vec3 as Add>::add` registered as `add`. `lt`, `le` and `concat` work the same way.
+const OPERATORS: &[(&str, &str)] = &[
+    ("__add", "add"),
+    ("__sub", "sub"),
+    ("__mul", "mul"),
+    ("__div", "div"),
+    ("__unm", "neg"),
+    ("__lt", "lt"),
+    ("__le", "le"),
+    ("__concat", "concat"),
+];
+
+/// Puts the operator metamethods on a `ReflectPtr` metatable. Numbers and strings, reflected
+/// or not, work like in plain lua, everything else goes to the functions registered for the
+/// type of either operand, left one first.
+pub fn set_operators<'gc>(ctx: Context<'gc>, metatable: &Table<'gc>) {
+    for (metamethod, name) in OPERATORS {
+        metatable
+            .set(ctx, *metamethod, operator(ctx, name))
+            .unwrap();
+    }
+    metatable
+        .set(
+            ctx,
+            "__eq",
+            Callback::from_fn(&ctx, |ctx, _fuel, mut stack| {
+                let (lhs, rhs): (Value, Value) = stack.consume(ctx)?;
+                let equal = match (reflect_ptr(lhs), reflect_ptr(rhs)) {
+                    (Some(lhs), Some(rhs)) => lhs
+                        .try_field_value_ref()?
+                        .reflect_partial_eq(rhs.try_field_value_ref()?.as_partial_reflect())
+                        .unwrap_or(false),
+                    _ => false,
+                };
+                stack.push_back(Value::Boolean(equal));
+                Ok(CallbackReturn::Return)
+            }),
+        )
+        .unwrap();
+}
+
+fn operator<'gc>(ctx: Context<'gc>, name: &'static str) -> Callback<'gc> {
+    Callback::from_fn(&ctx, move |ctx, _fuel, mut stack| {
+        let (lhs, rhs): (Value, Value) = stack.consume(ctx)?;
+        // `__unm` gets its operand twice
+        let args = if name == "neg" {
+            vec![lhs]
+        } else {
+            vec![lhs, rhs]
+        };
+        let plain = args
+            .iter()
+            .map(|arg| plain_value(ctx, *arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(value) = plain_operator(ctx, name, &plain)? {
+            stack.push_back(value);
+            return Ok(CallbackReturn::Return);
+        }
+        for arg in &args {
+            let Some(reflect_ptr) = reflect_ptr(*arg) else {
+                continue;
+            };
+            let type_id = reflect_ptr
+                .try_field_value_ref()?
+                .reflect_type_info()
+                .type_id();
+            let function_registry = reflect_ptr.function_registry();
+            let overloads = function_registry.borrow().overloads(type_id, name);
+            if let Some(overloads) = overloads {
+                for value in call_overloads(ctx, &overloads, args.clone(), &function_registry)? {
+                    stack.push_back(value);
+                }
+                return Ok(CallbackReturn::Return);
+            }
+        }
+        let types = args
+            .iter()
+            .map(|arg| match reflect_ptr(*arg) {
+                Some(reflect_ptr) => reflect_ptr
+                    .try_field_value_ref()
+                    .map(|value| value.reflect_type_path().to_string())
+                    .unwrap_or_default(),
+                None => arg.type_name().to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(" and ");
+        Err(anyhow!("there's no `{name}` registered for {types}").into())
+    })
+}
+
+fn reflect_ptr<'gc>(value: Value<'gc>) -> Option<&'gc ReflectPtr> {
+    match value {
+        Value::UserData(user_data) => user_data.downcast_static::<ReflectPtr>().ok(),
+        _ => None,
+    }
+}
+
+/// Reflected numbers and strings as lua ones.
+fn plain_value<'gc>(
+    ctx: Context<'gc>,
+    value: Value<'gc>,
+) -> Result<Value<'gc>, piccolo::Error<'gc>> {
+    let Some(reflect_ptr) = reflect_ptr(value) else {
+        return Ok(value);
+    };
+    let reflect = reflect_ptr.try_field_value_ref()?;
+    match primitive_to_lua(ctx, reflect.as_partial_reflect()) {
+        Some(plain @ (Value::Integer(_) | Value::Number(_) | Value::String(_))) => Ok(plain),
+        _ => Ok(value),
+    }
+}
+
+fn plain_operator<'gc>(
+    ctx: Context<'gc>,
+    name: &str,
+    args: &[Value<'gc>],
+) -> anyhow::Result<Option<Value<'gc>>> {
+    let number = |value: &Value| match value {
+        Value::Integer(i) => Some(*i as f64),
+        Value::Number(n) => Some(*n),
+        _ => None,
+    };
+    let value = match (name, args) {
+        ("neg", [Value::Integer(a)]) => Value::Integer(a.wrapping_neg()),
+        ("neg", [Value::Number(a)]) => Value::Number(-a),
+        ("concat", [a, b]) => match (plain_string(a), plain_string(b)) {
+            (Some(a), Some(b)) => Value::String(piccolo::String::from_slice(&ctx, a + &b)),
+            _ => return Ok(None),
+        },
+        ("lt" | "le", [Value::String(a), Value::String(b)]) => {
+            let (a, b) = (a.as_bytes(), b.as_bytes());
+            Value::Boolean(if name == "lt" { a < b } else { a <= b })
+        }
+        (_, [Value::Integer(a), Value::Integer(b)]) if name != "div" => match name {
+            "add" => Value::Integer(a.wrapping_add(*b)),
+            "sub" => Value::Integer(a.wrapping_sub(*b)),
+            "mul" => Value::Integer(a.wrapping_mul(*b)),
+            "lt" => Value::Boolean(a < b),
+            "le" => Value::Boolean(a <= b),
+            _ => return Ok(None),
+        },
+        (_, [a, b]) => {
+            let (Some(a), Some(b)) = (number(a), number(b)) else {
+                return Ok(None);
+            };
+            match name {
+                "add" => Value::Number(a + b),
+                "sub" => Value::Number(a - b),
+                "mul" => Value::Number(a * b),
+                "div" => Value::Number(a / b),
+                "lt" => Value::Boolean(a < b),
+                "le" => Value::Boolean(a <= b),
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(value))
+}
+
+fn plain_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(String::from_utf8_lossy(s.as_bytes()).into_owned()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Number(n) if n.fract() == 0.0 => Some(format!("{n:.1}")),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// `+`, `-`, `*`, `/` and unary `-` for the glam types scripts use the most, and `Quat * Vec3`.
+pub fn register_glam_operators(app: &mut App) {
+    macro_rules! operators {
+        ($ty:ty: $($name:literal => $function:expr),* $(,)?) => {$(
+            app.register_object_function::<$ty>($function.into_function().with_name($name));
+        )*};
+    }
+    operators!(Vec2:
+        "add" => <Vec2 as Add>::add,
+        "sub" => <Vec2 as Sub>::sub,
+        "mul" => <Vec2 as Mul>::mul,
+        "mul" => <Vec2 as Mul<f32>>::mul,
+        "mul" => <f32 as Mul<Vec2>>::mul,
+        "div" => <Vec2 as Div<f32>>::div,
+        "neg" => <Vec2 as Neg>::neg,
+    );
+    operators!(Vec3:
+        "add" => <Vec3 as Add>::add,
+        "sub" => <Vec3 as Sub>::sub,
+        "mul" => <Vec3 as Mul>::mul,
+        "mul" => <Vec3 as Mul<f32>>::mul,
+        "mul" => <f32 as Mul<Vec3>>::mul,
+        "div" => <Vec3 as Div<f32>>::div,
+        "neg" => <Vec3 as Neg>::neg,
+    );
+    operators!(Quat:
+        "mul" => <Quat as Mul>::mul,
+        "mul" => <Quat as Mul<Vec3>>::mul,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use piccolo::Lua;
+
+    #[test]
+    fn integer_ops_stay_integers() {
+        Lua::core().enter(|ctx| {
+            let op = |name, a, b| plain_operator(ctx, name, &[a, b]).unwrap();
+            let (two, three) = (Value::Integer(2), Value::Integer(3));
+            assert!(matches!(op("add", two, three), Some(Value::Integer(5))));
+            assert!(matches!(op("sub", two, three), Some(Value::Integer(-1))));
+            assert!(matches!(op("mul", two, three), Some(Value::Integer(6))));
+            assert!(matches!(op("lt", two, three), Some(Value::Boolean(true))));
+            assert!(matches!(op("le", three, two), Some(Value::Boolean(false))));
+            // division is always float division in lua
+            assert!(matches!(op("div", three, two), Some(Value::Number(n)) if n == 1.5));
+            assert!(matches!(
+                op("add", Value::Integer(i64::MAX), Value::Integer(1)),
+                Some(Value::Integer(i64::MIN))
+            ));
+            let neg = plain_operator(ctx, "neg", &[three]).unwrap();
+            assert!(matches!(neg, Some(Value::Integer(-3))));
+        });
+    }
+
+    #[test]
+    fn mixed_ops_are_floats() {
+        Lua::core().enter(|ctx| {
+            let op = |name, a, b| plain_operator(ctx, name, &[a, b]).unwrap();
+            let (two, half) = (Value::Integer(2), Value::Number(0.5));
+            assert!(matches!(op("add", two, half), Some(Value::Number(n)) if n == 2.5));
+            assert!(matches!(op("mul", half, two), Some(Value::Number(n)) if n == 1.0));
+            assert!(matches!(op("lt", half, two), Some(Value::Boolean(true))));
+            assert!(op("add", two, Value::Boolean(true)).is_none());
+            assert!(op("pow", two, half).is_none());
+        });
+    }
+
+    #[test]
+    fn concat_and_string_compares() {
+        Lua::core().enter(|ctx| {
+            let string = |s: &str| Value::String(piccolo::String::from_slice(&ctx, s));
+            let op = |name, a, b| plain_operator(ctx, name, &[a, b]).unwrap();
+            let Some(Value::String(joined)) = op("concat", string("n = "), Value::Integer(3))
+            else {
+                panic!("expected a string");
+            };
+            assert_eq!(joined.as_bytes(), b"n = 3");
+            assert!(matches!(
+                op("lt", string("a"), string("b")),
+                Some(Value::Boolean(true))
+            ));
+            assert!(op("concat", string("a"), Value::Nil).is_none());
+        });
+    }
+
+    #[test]
+    fn numbers_as_strings() {
+        assert_eq!(plain_string(&Value::Integer(3)).as_deref(), Some("3"));
+        assert_eq!(plain_string(&Value::Number(3.0)).as_deref(), Some("3.0"));
+        assert_eq!(plain_string(&Value::Number(0.25)).as_deref(), Some("0.25"));
+        assert_eq!(plain_string(&Value::Boolean(true)), None);
+    }
+}
@@ -1,46 +1,657 @@
+use crate::args_stuff::lua_to_owned;
+use crate::collection_stuff::{collection_method, len, pairs};
+use crate::construct_stuff::{constructor, default_constructor, is_constructible};
+use crate::enum_stuff::{enum_method, variant_index};
+use crate::ops_stuff::set_operators;
+use crate::return_stuff::concrete;
 use crate::userdata_stuff::{UserDataPtr, ValueExt};
 use crate::{lua_wrapped_dynamic_function_call, HashMapWrapper, LuaVm, TableReflectWrapper};
-use bevy::ecs::component::{ComponentDescriptor, ComponentId};
+use anyhow::{anyhow, bail};
+use bevy::ecs::change_detection::MutUntyped;
+use bevy::ecs::component::{ComponentDescriptor, ComponentId, Tick};
+use bevy::ecs::entity::Entities;
 use bevy::ecs::prelude::AppFunctionRegistry;
+use bevy::ecs::schedule::{InternedScheduleLabel, InternedSystemSet, ScheduleLabel};
 use bevy::ecs::world::{CommandQueue, FilteredEntityMut};
 use bevy::prelude::*;
-use bevy::reflect::func::{ArgList, FunctionRegistry, Return};
+use bevy::reflect::func::{ArgList, DynamicFunction, FunctionRegistry, Return};
+use bevy::reflect::{
+    Access, GetPath, ParsedPath, ReflectFromPtr, ReflectMut, ReflectRef, TypeInfo,
+};
 use piccolo::{
     Callback, CallbackReturn, Context, FromValue, Function, IntoValue, StashedFunction, Table,
     TypeError, UserData, Value, Variadic,
 };
 use send_wrapper::SendWrapper;
 use std::any::{Any, TypeId};
+use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
 use std::rc::Rc;
 use std::sync::Arc;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ComponentType {
     Ref((ComponentId, TypeId)),
     Mut((ComponentId, TypeId)),
+    /// Like `Option<&T>`, rows without the component get `nil`.
+    OptRef((ComponentId, TypeId)),
+    /// Like `Option<&mut T>`, rows without the component get `nil`.
+    OptMut((ComponentId, TypeId)),
+}
+
+impl ComponentType {
+    pub fn ids(&self) -> (ComponentId, TypeId) {
+        match self {
+            ComponentType::Ref(ids)
+            | ComponentType::Mut(ids)
+            | ComponentType::OptRef(ids)
+            | ComponentType::OptMut(ids) => *ids,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct CommandQueueMarker;
 
+/// The `Entity` global, asks a query for the id of each row.
+#[derive(Copy, Clone, Debug)]
+pub struct EntityMarker;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum QueryTerm {
+    Entity,
+    Component(ComponentType),
+}
+
+/// A query parameter of a lua system, e.g. `{Transform.mut, with = {Player}, changed = {Velocity}}`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LuaQuery {
+    pub terms: Vec<QueryTerm>,
+    pub with: Vec<ComponentId>,
+    pub without: Vec<ComponentId>,
+    /// There's no dynamic `Changed`/`Added` filter, so these get read by the query and the
+    /// ticks are checked against the system's last run while iterating.
+    pub changed: Vec<ComponentId>,
+    pub added: Vec<ComponentId>,
+    /// Matches entities with at least one of these.
+    pub or: Vec<ComponentId>,
+}
+
+impl LuaQuery {
+    pub fn build(&self, builder: &mut QueryBuilder<FilteredEntityMut>) {
+        for term in &self.terms {
+            match term {
+                QueryTerm::Entity => {}
+                QueryTerm::Component(ComponentType::Ref((component_id, _))) => {
+                    builder.ref_id(*component_id);
+                }
+                QueryTerm::Component(ComponentType::Mut((component_id, _))) => {
+                    builder.mut_id(*component_id);
+                }
+                QueryTerm::Component(ComponentType::OptRef((component_id, _))) => {
+                    builder.optional(|builder| {
+                        builder.ref_id(*component_id);
+                    });
+                }
+                QueryTerm::Component(ComponentType::OptMut((component_id, _))) => {
+                    builder.optional(|builder| {
+                        builder.mut_id(*component_id);
+                    });
+                }
+            }
+        }
+        for component_id in &self.with {
+            builder.with_id(*component_id);
+        }
+        for component_id in &self.without {
+            builder.without_id(*component_id);
+        }
+        for component_id in self.changed.iter().chain(&self.added) {
+            builder.ref_id(*component_id);
+        }
+        if !self.or.is_empty() {
+            builder.or(|builder| {
+                for component_id in &self.or {
+                    builder.with_id(*component_id);
+                }
+            });
+        }
+    }
+
+    /// The components this query reads and the ones it writes.
+    fn access(&self) -> (Vec<ComponentId>, Vec<ComponentId>) {
+        let mut reads = self.changed.clone();
+        reads.extend(&self.added);
+        let mut writes = vec![];
+        for term in &self.terms {
+            match term {
+                QueryTerm::Entity => {}
+                QueryTerm::Component(ComponentType::Ref((component_id, _)))
+                | QueryTerm::Component(ComponentType::OptRef((component_id, _))) => {
+                    reads.push(*component_id);
+                }
+                QueryTerm::Component(ComponentType::Mut((component_id, _)))
+                | QueryTerm::Component(ComponentType::OptMut((component_id, _))) => {
+                    writes.push(*component_id);
+                }
+            }
+        }
+        (reads, writes)
+    }
+
+    /// The components one of the queries writes and the other one uses.
+    fn conflicts(&self, other: &LuaQuery) -> Vec<ComponentId> {
+        let (reads, writes) = self.access();
+        let (other_reads, other_writes) = other.access();
+        let mut conflicts = vec![];
+        for component_id in writes.iter().chain(&other_writes) {
+            let used_by_self = reads.contains(component_id) || writes.contains(component_id);
+            let used_by_other =
+                other_reads.contains(component_id) || other_writes.contains(component_id);
+            if used_by_self && used_by_other && !conflicts.contains(component_id) {
+                conflicts.push(*component_id);
+            }
+        }
+        conflicts
+    }
+
+    /// Whether the `changed` and `added` filters pass for this row.
+    pub fn matches_ticks(
+        &self,
+        entity: &FilteredEntityMut,
+        last_run: Tick,
+        this_run: Tick,
+    ) -> bool {
+        self.changed.iter().all(|component_id| {
+            entity
+                .get_change_ticks_by_id(*component_id)
+                .is_some_and(|ticks| ticks.is_changed(last_run, this_run))
+        }) && self.added.iter().all(|component_id| {
+            entity
+                .get_change_ticks_by_id(*component_id)
+                .is_some_and(|ticks| ticks.is_added(last_run, this_run))
+        })
+    }
+}
+
+/// The schedules a lua system can be registered into, picked with
+/// `app:register_system(f, params, { schedule = "FixedUpdate" })`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LuaSchedule {
+    /// Runs once every time the script is loaded, instead of once per app.
+    Startup,
+    PreUpdate,
+    Update,
+    FixedUpdate,
+    PostUpdate,
+    Last,
+}
+
+impl LuaSchedule {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Startup" => Some(Self::Startup),
+            "PreUpdate" => Some(Self::PreUpdate),
+            "Update" => Some(Self::Update),
+            "FixedUpdate" => Some(Self::FixedUpdate),
+            "PostUpdate" => Some(Self::PostUpdate),
+            "Last" => Some(Self::Last),
+            _ => None,
+        }
+    }
+
+    /// The bevy schedule these systems get added to, `None` for [`LuaSchedule::Startup`]
+    /// since those are run directly when the script loads.
+    pub fn label(self) -> Option<InternedScheduleLabel> {
+        match self {
+            Self::Startup => None,
+            Self::PreUpdate => Some(PreUpdate.intern()),
+            Self::Update => Some(Update.intern()),
+            Self::FixedUpdate => Some(FixedUpdate.intern()),
+            Self::PostUpdate => Some(PostUpdate.intern()),
+            Self::Last => Some(Last.intern()),
+        }
+    }
+}
+
+pub type LuaSystems = HashMap<LuaSchedule, Vec<LuaSystem>>;
+
+#[derive(Clone)]
 pub struct LuaSystem {
     pub lua_func: StashedFunction,
     pub system_parameters: Vec<SystemParameter>,
+    /// Puts the system in a [`LuaSystemSet`] of the same name so other systems can order against it.
+    pub name: Option<String>,
+    /// Names of lua systems or sets exposed with `expose_system_set_to_lua`.
+    pub after: Vec<String>,
+    pub before: Vec<String>,
+    pub in_sets: Vec<String>,
+    pub run_if: Option<LuaRunCondition>,
+}
+
+impl LuaSystem {
+    /// Whether the two only differ in their lua functions, so a reloaded script can keep the
+    /// system that's already scheduled.
+    pub fn same_shape(&self, other: &LuaSystem) -> bool {
+        self.system_parameters == other.system_parameters
+            && self.name == other.name
+            && self.after == other.after
+            && self.before == other.before
+            && self.in_sets == other.in_sets
+            && match (&self.run_if, &other.run_if) {
+                (None, None) => true,
+                (Some(run_if), Some(other)) => run_if.same_shape(other),
+                _ => false,
+            }
+    }
+
+    pub fn ordering(&self) -> LuaOrdering {
+        LuaOrdering {
+            name: self.name.clone(),
+            sets: self.name.iter().chain(&self.in_sets).cloned().collect(),
+            after: self.after.clone(),
+            before: self.before.clone(),
+        }
+    }
+}
+
+/// Where a lua system sits in its schedule, kept for as long as the system is in it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LuaOrdering {
+    pub name: Option<String>,
+    /// Its name and `in_set`s.
+    pub sets: Vec<String>,
+    pub after: Vec<String>,
+    pub before: Vec<String>,
+}
+
+impl LuaOrdering {
+    /// Whether `self` has to run before `other`.
+    fn runs_before(&self, other: &LuaOrdering) -> bool {
+        other.after.iter().any(|set| self.sets.contains(set))
+            || self.before.iter().any(|set| other.sets.contains(set))
+    }
+
+    fn describe(&self) -> String {
+        match (&self.name, self.sets.first()) {
+            (Some(name), _) => format!("{name:?}"),
+            (None, Some(set)) => format!("a system in {set:?}"),
+            (None, None) => "an unnamed system".to_string(),
+        }
+    }
+}
+
+/// Bevy panics on a schedule whose systems have to run before themselves, like
+/// `{ name = "A", after = "A" }` or two systems each `before` the other. This finds such a
+/// cycle among lua systems of one schedule and describes it. Cycles going through rust
+/// systems in exposed sets can't be seen from here.
+pub fn find_ordering_cycle(systems: &[LuaOrdering]) -> Option<String> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Visit {
+        New,
+        InProgress,
+        Done,
+    }
+    fn visit(
+        systems: &[LuaOrdering],
+        i: usize,
+        visits: &mut [Visit],
+        path: &mut Vec<usize>,
+    ) -> Option<Vec<usize>> {
+        visits[i] = Visit::InProgress;
+        path.push(i);
+        for j in 0..systems.len() {
+            if !systems[i].runs_before(&systems[j]) {
+                continue;
+            }
+            match visits[j] {
+                Visit::InProgress => {
+                    let start = path.iter().position(|k| *k == j).unwrap();
+                    let mut cycle = path[start..].to_vec();
+                    cycle.push(j);
+                    return Some(cycle);
+                }
+                Visit::New => {
+                    if let Some(cycle) = visit(systems, j, visits, path) {
+                        return Some(cycle);
+                    }
+                }
+                Visit::Done => {}
+            }
+        }
+        path.pop();
+        visits[i] = Visit::Done;
+        None
+    }
+    let mut visits = vec![Visit::New; systems.len()];
+    for i in 0..systems.len() {
+        if visits[i] != Visit::New {
+            continue;
+        }
+        if let Some(cycle) = visit(systems, i, &mut visits, &mut vec![]) {
+            let names = cycle
+                .into_iter()
+                .map(|i| systems[i].describe())
+                .collect::<Vec<_>>();
+            return Some(names.join(" runs before "));
+        }
+    }
+    None
+}
+
+/// Decides whether a lua system runs this tick, set with the `run_if` option.
+#[derive(Clone)]
+pub enum LuaRunCondition {
+    /// A lua function that gets its own `run_if_params` and returns whether the system should run.
+    Lua {
+        lua_func: StashedFunction,
+        system_parameters: Vec<SystemParameter>,
+    },
+    EveryNFrames(u32),
+    OnTimer(f32),
+    ResourceExists(ComponentId),
+    InState {
+        resource: ComponentType,
+        variant: String,
+    },
+}
+
+impl LuaRunCondition {
+    fn same_shape(&self, other: &LuaRunCondition) -> bool {
+        match (self, other) {
+            (
+                LuaRunCondition::Lua {
+                    system_parameters, ..
+                },
+                LuaRunCondition::Lua {
+                    system_parameters: other,
+                    ..
+                },
+            ) => system_parameters == other,
+            (LuaRunCondition::EveryNFrames(n), LuaRunCondition::EveryNFrames(other)) => n == other,
+            (LuaRunCondition::OnTimer(seconds), LuaRunCondition::OnTimer(other)) => {
+                seconds == other
+            }
+            (
+                LuaRunCondition::ResourceExists(component_id),
+                LuaRunCondition::ResourceExists(other),
+            ) => component_id == other,
+            (
+                LuaRunCondition::InState { resource, variant },
+                LuaRunCondition::InState {
+                    resource: other_resource,
+                    variant: other_variant,
+                },
+            ) => resource == other_resource && variant == other_variant,
+            _ => false,
+        }
+    }
+
+    /// The `Conditions` global, e.g. `run_if = Conditions.on_timer(2.5)`.
+    pub fn constructors<'gc>(ctx: &Context<'gc>) -> Table<'gc> {
+        let conditions = Table::new(ctx);
+        conditions
+            .set(
+                *ctx,
+                "every_n_frames",
+                Callback::from_fn(ctx, |ctx, _fuel, mut stack| {
+                    let n: i64 = stack.consume(ctx)?;
+                    let n = u32::try_from(n).ok().filter(|n| *n > 0).ok_or_else(|| {
+                        anyhow!(
+                            "every_n_frames takes a whole number from 1 to {}, got {n}",
+                            u32::MAX
+                        )
+                    })?;
+                    let condition = LuaRunCondition::EveryNFrames(n);
+                    stack.push_front(UserData::new_static(&ctx, condition).into());
+                    Ok(CallbackReturn::Return)
+                }),
+            )
+            .unwrap();
+        conditions
+            .set(
+                *ctx,
+                "on_timer",
+                Callback::from_fn(ctx, |ctx, _fuel, mut stack| {
+                    let seconds: f64 = stack.consume(ctx)?;
+                    // `on_timer` panics on negative, NaN and too long durations
+                    if std::time::Duration::try_from_secs_f32(seconds as f32).is_err() {
+                        return Err(anyhow!(
+                            "on_timer takes a number of seconds that's 0 or more, got {seconds}"
+                        )
+                        .into());
+                    }
+                    let condition = LuaRunCondition::OnTimer(seconds as f32);
+                    stack.push_front(UserData::new_static(&ctx, condition).into());
+                    Ok(CallbackReturn::Return)
+                }),
+            )
+            .unwrap();
+        conditions
+            .set(
+                *ctx,
+                "resource_exists",
+                Callback::from_fn(ctx, |ctx, _fuel, mut stack| {
+                    let resource: UserData = stack.consume(ctx)?;
+                    let (component_id, _) = resource.downcast_static::<ComponentType>()?.ids();
+                    let condition = LuaRunCondition::ResourceExists(component_id);
+                    stack.push_front(UserData::new_static(&ctx, condition).into());
+                    Ok(CallbackReturn::Return)
+                }),
+            )
+            .unwrap();
+        conditions
+            .set(
+                *ctx,
+                "in_state",
+                Callback::from_fn(ctx, |ctx, _fuel, mut stack| {
+                    let (resource, variant): (UserData, piccolo::String) = stack.consume(ctx)?;
+                    let condition = LuaRunCondition::InState {
+                        resource: *resource.downcast_static::<ComponentType>()?,
+                        variant: variant.to_str()?.to_string(),
+                    };
+                    stack.push_front(UserData::new_static(&ctx, condition).into());
+                    Ok(CallbackReturn::Return)
+                }),
+            )
+            .unwrap();
+        conditions
+    }
+}
+
+/// Bevy panics on a system whose queries could hand out the same component mutably twice,
+/// like `{Transform.mut}` next to `{Transform.ref}`, so that's a lua error when it's registered.
+fn check_query_conflicts(
+    world: &mut World,
+    system_parameters: &[SystemParameter],
+) -> anyhow::Result<()> {
+    let queries = system_parameters
+        .iter()
+        .filter_map(|system_parameter| match system_parameter {
+            SystemParameter::Query(lua_query) => Some(lua_query),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let accesses = queries
+        .iter()
+        .map(|lua_query| {
+            let mut builder = QueryBuilder::<FilteredEntityMut>::new(world);
+            lua_query.build(&mut builder);
+            builder.access().clone()
+        })
+        .collect::<Vec<_>>();
+    for (i, access) in accesses.iter().enumerate() {
+        for (j, other) in accesses.iter().enumerate().skip(i + 1) {
+            if access.is_compatible(other) {
+                continue;
+            }
+            let names = queries[i]
+                .conflicts(queries[j])
+                .into_iter()
+                .map(
+                    |component_id| match world.components().get_name(component_id) {
+                        Some(name) => name.to_string(),
+                        None => format!("{component_id:?}"),
+                    },
+                )
+                .collect::<Vec<_>>()
+                .join(", ");
+            bail!(
+                "queries {} and {} of this system both use {names} and one of them mutably, \
+                keep them apart with `without` or use a single query",
+                i + 1,
+                j + 1
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Reads the parameter list of a lua system or run condition.
+fn parse_system_parameters<'gc>(
+    ctx: Context<'gc>,
+    system_params: Table<'gc>,
+) -> Result<Vec<SystemParameter>, piccolo::Error<'gc>> {
+    let mut system_parameters = vec![];
+
+    for (_, system_parameter) in system_params.into_iter() {
+        if system_parameter
+            .as_static_user_data::<CommandQueueMarker>()
+            .is_ok()
+        {
+            system_parameters.push(SystemParameter::CommandQueue);
+            continue;
+        }
+        if let Ok(resource_component_type) = system_parameter.as_static_user_data::<ComponentType>()
+        {
+            system_parameters.push(SystemParameter::Resource(resource_component_type.clone()));
+            continue;
+        }
+
+        let table = Table::from_value(ctx, system_parameter)?;
+        // the query itself gets built when the system is added to a schedule
+        let mut lua_query = LuaQuery::default();
+        for (key, value) in table.into_iter() {
+            let Value::String(filter) = key else {
+                if value.as_static_user_data::<EntityMarker>().is_ok() {
+                    lua_query.terms.push(QueryTerm::Entity);
+                    continue;
+                }
+                let component_type = UserData::from_value(ctx, value)?;
+                let component_type = component_type
+                    .downcast_static::<ComponentType>()
+                    .unwrap()
+                    .clone();
+                lua_query.terms.push(QueryTerm::Component(component_type));
+                continue;
+            };
+            let filter_ids = filter_component_ids(ctx, value)?;
+            match filter.to_str()? {
+                "with" => lua_query.with.extend(filter_ids),
+                "without" => lua_query.without.extend(filter_ids),
+                "changed" => lua_query.changed.extend(filter_ids),
+                "added" => lua_query.added.extend(filter_ids),
+                "or" => lua_query.or.extend(filter_ids),
+                other => return Err(anyhow::anyhow!("unknown query filter: {other}").into()),
+            }
+        }
+        // lua's `for` stops at a leading `nil`, which is what a missing optional component is
+        if let Some(QueryTerm::Component(ComponentType::OptRef(_) | ComponentType::OptMut(_))) =
+            lua_query.terms.first()
+        {
+            return Err(anyhow::anyhow!(
+                "a query can't start with an optional component, put `Entity` or a required one first"
+            )
+            .into());
+        }
+        system_parameters.push(SystemParameter::Query(lua_query));
+    }
+    Ok(system_parameters)
 }
 
+/// Reads the components of a query filter like `with = {Player, Enemy.ref}`, either the
+/// `ref`/`mut` markers or the namespace table of the type itself work.
+fn filter_component_ids<'gc>(
+    ctx: Context<'gc>,
+    filter: Value<'gc>,
+) -> Result<Vec<ComponentId>, piccolo::Error<'gc>> {
+    let mut ids = vec![];
+    for (_, component) in Table::from_value(ctx, filter)? {
+        let component = match component {
+            Value::Table(namespace) => namespace.get::<_, Value>(ctx, "ref")?,
+            component => component,
+        };
+        let (component_id, _) = component.as_static_user_data::<ComponentType>()?.ids();
+        ids.push(component_id);
+    }
+    Ok(ids)
+}
+
+/// The set every named lua system is put in, and the fallback for any set name
+/// that wasn't exposed from rust.
+#[derive(SystemSet, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LuaSystemSet(pub String);
+
+/// Rust system sets that lua systems can refer to by name.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct LuaSystemSets(HashMap<String, InternedSystemSet>);
+
+impl LuaSystemSets {
+    pub fn resolve(world: &World, name: &str) -> InternedSystemSet {
+        world
+            .get_resource::<LuaSystemSets>()
+            .and_then(|sets| sets.get(name).copied())
+            .unwrap_or_else(|| LuaSystemSet(name.to_string()).intern())
+    }
+}
+
+/// Reads either a single set name or a list of them from a `register_system` option.
+fn system_set_names<'gc>(value: Value<'gc>) -> Result<Vec<String>, piccolo::Error<'gc>> {
+    match value {
+        Value::Nil => Ok(vec![]),
+        Value::String(name) => Ok(vec![name.to_str()?.to_string()]),
+        Value::Table(names) => {
+            let mut out = vec![];
+            for (_, name) in names {
+                let Value::String(name) = name else {
+                    return Err(anyhow::anyhow!("system set names must be strings").into());
+                };
+                out.push(name.to_str()?.to_string());
+            }
+            Ok(out)
+        }
+        _ => Err(anyhow::anyhow!("expected a system set name or a list of them").into()),
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub enum SystemParameter {
-    Query((QueryState<FilteredEntityMut<'static>>, Vec<ComponentType>)),
+    Query(LuaQuery),
     CommandQueue,
     Resource(ComponentType),
 }
 
 pub struct ReflectPtr {
     pub data: ReflectType,
-    path: String,
+    path: Vec<PathKey>,
     ptr_state: Rc<RefCell<PtrState>>,
     function_registry: Rc<RefCell<ObjectFunctionRegistry>>,
+    changed: Option<ChangeMarker>,
+}
+
+/// The change ticks of a component or resource lua got mutable access to. It's only marked
+/// changed when lua writes to it, handing out the pointer with `as_mut` would mark it every run.
+#[derive(Clone)]
+pub struct ChangeMarker(Rc<RefCell<MutUntyped<'static>>>);
+
+impl ChangeMarker {
+    /// Only used while the `ptr_state` of the `ReflectPtr` holding it is valid.
+    unsafe fn new(value: MutUntyped) -> Self {
+        let value = std::mem::transmute::<MutUntyped<'_>, MutUntyped<'static>>(value);
+        Self(Rc::new(RefCell::new(value)))
+    }
 }
 
 pub enum ReflectType {
@@ -61,7 +672,39 @@ impl Clone for ReflectType {
 
 #[derive(Default, Deref, DerefMut)]
 pub struct ObjectFunctionRegistry {
+    #[deref]
     map: HashMap<TypeId, FunctionRegistry>,
+    /// Functions registered under a name that was already taken for the type.
+    overloads: HashMap<(TypeId, String), Vec<DynamicFunction<'static>>>,
+}
+
+impl ObjectFunctionRegistry {
+    /// A function with a name that's already registered for `type_id` becomes an overload,
+    /// lua calls go to the first one the arguments fit.
+    pub fn register(&mut self, type_id: TypeId, function: DynamicFunction<'static>) {
+        let name = function
+            .name()
+            .expect("functions for lua need a name")
+            .to_string();
+        let function_registry = self.map.entry(type_id).or_default();
+        if function_registry.contains(&name) {
+            self.overloads
+                .entry((type_id, name))
+                .or_default()
+                .push(function);
+        } else {
+            function_registry.register(function).unwrap();
+        }
+    }
+
+    /// Every function registered under `name` for `type_id`, in the order they were registered.
+    pub fn overloads(&self, type_id: TypeId, name: &str) -> Option<Vec<DynamicFunction<'static>>> {
+        let mut overloads = vec![self.map.get(&type_id)?.get(name)?.clone()];
+        if let Some(more) = self.overloads.get(&(type_id, name.to_string())) {
+            overloads.extend(more.iter().cloned());
+        }
+        Some(overloads)
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -78,11 +721,27 @@ impl ReflectPtr {
     ) -> Self {
         Self {
             data: ReflectType::PtrMut(reflect as *mut dyn Reflect),
-            path: "".to_string(),
+            path: vec![],
             ptr_state,
             function_registry,
+            changed: None,
         }
     }
+    /// Points at a component or resource, which gets marked changed when it's written to.
+    ///
+    /// # Safety
+    /// `reflect_from_ptr` has to be for the type of `value`.
+    pub unsafe fn new_tracked(
+        mut value: MutUntyped,
+        reflect_from_ptr: &ReflectFromPtr,
+        ptr_state: Rc<RefCell<PtrState>>,
+        function_registry: Rc<RefCell<ObjectFunctionRegistry>>,
+    ) -> Self {
+        let reflect = reflect_from_ptr.as_reflect_mut(value.bypass_change_detection().reborrow());
+        let mut reflect_ptr = Self::new_mut(reflect, ptr_state, function_registry);
+        reflect_ptr.changed = Some(ChangeMarker::new(value));
+        reflect_ptr
+    }
     pub fn new_ref(
         reflect: &dyn Reflect,
         ptr_state: Rc<RefCell<PtrState>>,
@@ -90,9 +749,10 @@ impl ReflectPtr {
     ) -> Self {
         Self {
             data: ReflectType::PtrRef(reflect as *const dyn Reflect),
-            path: "".to_string(),
+            path: vec![],
             ptr_state,
             function_registry,
+            changed: None,
         }
     }
     pub fn new_boxed(
@@ -102,52 +762,266 @@ impl ReflectPtr {
     ) -> Self {
         Self {
             data: ReflectType::Boxed(Rc::new(RefCell::new(Some(reflect)))),
-            path: "".to_string(),
+            path: vec![],
             ptr_state,
             function_registry,
+            changed: None,
         }
     }
-    pub fn get_field_value_ref(&self) -> &dyn Reflect {
-        if &*self.ptr_state.borrow() == &PtrState::Invalid {
-            panic!("invalid pointer state, saved outside of valid area")
+    pub fn ptr_state(&self) -> Rc<RefCell<PtrState>> {
+        self.ptr_state.clone()
+    }
+    pub fn function_registry(&self) -> Rc<RefCell<ObjectFunctionRegistry>> {
+        self.function_registry.clone()
+    }
+    /// Whether it points at a whole value rather than a field of one.
+    pub fn is_root(&self) -> bool {
+        self.path.is_empty()
+    }
+    /// Whether it can be passed to `&mut` parameters.
+    pub fn is_mutable(&self) -> bool {
+        !matches!(self.data, ReflectType::PtrRef(_))
+    }
+    /// The value at `key` in this one, sharing its `ptr_state`.
+    pub fn child(&self, key: PathKey) -> anyhow::Result<ReflectPtr> {
+        let mut reflect_ptr = self.clone();
+        reflect_ptr.path.push(key);
+        reflect_ptr.try_field_value_ref()?;
+        Ok(reflect_ptr)
+    }
+    /// A copy of the value for passing to a function by value, if the type has
+    /// `ReflectFromReflect`. `None` means it can't be copied but can be moved out with `take`,
+    /// which is left to the caller so checking whether a call fits doesn't move anything.
+    pub fn owned_clone(&self, ctx: Context) -> anyhow::Result<Option<Box<dyn PartialReflect>>> {
+        let value = self.try_field_value_ref()?;
+        let type_path = value.reflect_type_path().to_string();
+        let cloned = concrete(ctx, value.clone_value());
+        if !cloned.is_dynamic() {
+            return Ok(Some(cloned));
         }
-        let mut reflect = unsafe { &*self.get_data() };
-        for field in self.path.split(".") {
-            if field.is_empty() {
-                continue;
+        if matches!(self.data, ReflectType::Boxed(_)) && self.path.is_empty() {
+            return Ok(None);
+        }
+        Err(anyhow::anyhow!(
+            "{type_path} can't be passed by value, it isn't ReflectFromReflect"
+        ))
+    }
+    /// Moves a boxed value out of lua, using it afterwards is an error.
+    pub fn take(&self) -> anyhow::Result<Box<dyn PartialReflect>> {
+        self.check_valid()?;
+        match &self.data {
+            ReflectType::Boxed(boxed) if self.path.is_empty() => {
+                let value = boxed.borrow_mut().take().unwrap();
+                Ok(value.into_partial_reflect())
             }
-            reflect = reflect
-                .as_reflect()
-                .reflect_ref()
-                .as_struct()
-                .unwrap()
-                .field(field)
-                .unwrap()
-                .try_as_reflect()
-                .unwrap();
+            _ => bail!("only whole boxed values can be moved"),
         }
-        reflect
     }
-    pub fn get_field_value_mut(&self) -> &mut dyn Reflect {
+    /// Errors instead of dereferencing a pointer that's outlived its system, or a boxed value
+    /// that `take` moved out.
+    fn check_valid(&self) -> anyhow::Result<()> {
         if &*self.ptr_state.borrow() == &PtrState::Invalid {
-            panic!("invalid pointer state, saved outside of valid area")
+            bail!("invalid pointer state, saved outside of valid area")
         }
-        let mut reflect = unsafe { &mut *self.get_data_mut().unwrap() };
-        for field in self.path.split(".") {
-            if field.is_empty() {
-                continue;
+        if let ReflectType::Boxed(boxed) = &self.data {
+            if boxed.borrow().is_none() {
+                bail!("value was moved into a function call")
             }
-            reflect = reflect
-                .as_reflect_mut()
-                .reflect_mut()
-                .as_struct()
-                .unwrap()
-                .field_mut(field)
-                .unwrap()
-                .try_as_reflect_mut()
-                .unwrap();
         }
+        Ok(())
+    }
+    /// Follows `path` from the value, erroring when a field is missing.
+    pub fn try_field_value_ref(&self) -> anyhow::Result<&dyn Reflect> {
+        self.check_valid()?;
+        let mut reflect = unsafe { &*self.get_data() }.as_partial_reflect();
+        for key in &self.path {
+            reflect = key.field(reflect)?;
+        }
+        reflect
+            .try_as_reflect()
+            .ok_or_else(|| anyhow!("{} isn't a full Reflect type", reflect.reflect_type_path()))
+    }
+    pub fn try_field_value_mut(&self) -> anyhow::Result<&mut dyn Reflect> {
+        self.check_valid()?;
+        let reflect = self
+            .get_data_mut()
+            .ok_or_else(|| anyhow!("can't change a value that was only borrowed"))?;
+        if let Some(changed) = &self.changed {
+            changed.0.borrow_mut().set_changed();
+        }
+        let mut reflect = unsafe { &mut *reflect }.as_partial_reflect_mut();
+        for key in &self.path {
+            reflect = key.field_mut(reflect)?;
+        }
+        let type_path = reflect.reflect_type_path().to_string();
         reflect
+            .try_as_reflect_mut()
+            .ok_or_else(|| anyhow!("{type_path} isn't a full Reflect type"))
+    }
+}
+
+/// One step of a `ReflectPtr`'s path, from indexing it in lua with a string or an integer.
+#[derive(Clone, Debug)]
+pub enum PathKey {
+    /// A struct field, enum variant field or string map key. `"0"` is a tuple field.
+    Name(String),
+    /// Starts at 1 like lua, for list items, tuple fields and struct fields in order, or an
+    /// integer map key.
+    Index(i64),
+}
+
+impl PathKey {
+    pub fn from_lua(key: Value) -> anyhow::Result<Self> {
+        match key {
+            Value::String(name) => Ok(PathKey::Name(name.to_str()?.to_string())),
+            Value::Integer(i) => Ok(PathKey::Index(i)),
+            Value::Number(n) if n.fract() == 0.0 => Ok(PathKey::Index(n as i64)),
+            key => bail!(
+                "can't index a reflected value with a lua {}",
+                key.type_name()
+            ),
+        }
+    }
+
+    /// The `GetPath` access for this key on `reflect`, `None` for maps which it can't index.
+    fn parsed_path(&self, reflect: &dyn PartialReflect) -> anyhow::Result<Option<ParsedPath>> {
+        let access = match (self, reflect.reflect_ref()) {
+            (_, ReflectRef::Map(_)) => return Ok(None),
+            (PathKey::Name(name), _) => match name.parse() {
+                Ok(i) => Access::TupleIndex(i),
+                Err(_) => Access::Field(name.clone().into()),
+            },
+            (PathKey::Index(i), ReflectRef::List(_) | ReflectRef::Array(_)) => {
+                Access::ListIndex(Self::position(*i)?)
+            }
+            (PathKey::Index(i), ReflectRef::Struct(_)) => Access::FieldIndex(Self::position(*i)?),
+            (PathKey::Index(i), _) => Access::TupleIndex(Self::position(*i)?),
+        };
+        Ok(Some(ParsedPath::from(vec![access])))
+    }
+
+    /// Map keys that are strings or integers.
+    pub fn from_reflect(key: &dyn PartialReflect) -> Option<Self> {
+        if let Some(name) = key.try_downcast_ref::<String>() {
+            return Some(PathKey::Name(name.clone()));
+        }
+        if let Some(name) = key.try_downcast_ref::<&'static str>() {
+            return Some(PathKey::Name(name.to_string()));
+        }
+        if let Some(name) = key.try_downcast_ref::<Cow<'static, str>>() {
+            return Some(PathKey::Name(name.to_string()));
+        }
+        macro_rules! integers {
+            ($($int:ty),*) => {$(
+                if let Some(i) = key.try_downcast_ref::<$int>() {
+                    return i64::try_from(*i).ok().map(PathKey::Index);
+                }
+            )*};
+        }
+        integers!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+        None
+    }
+
+    pub fn into_lua<'gc>(self, ctx: Context<'gc>) -> Value<'gc> {
+        match self {
+            PathKey::Name(name) => Value::String(piccolo::String::from_slice(&ctx, name)),
+            PathKey::Index(i) => Value::Integer(i),
+        }
+    }
+
+    /// Past the end of a list or not in a map, which is `nil` in lua rather than an error.
+    pub fn is_missing_entry(&self, reflect: &dyn PartialReflect) -> bool {
+        let len = match reflect.reflect_ref() {
+            ReflectRef::Map(map) => return !map.iter().any(|(key, _)| self.is_map_key(key)),
+            ReflectRef::List(list) => list.len(),
+            ReflectRef::Array(array) => array.len(),
+            _ => return false,
+        };
+        match self {
+            PathKey::Index(i) => *i < 1 || *i as u64 > len as u64,
+            PathKey::Name(_) => false,
+        }
+    }
+
+    fn position(i: i64) -> anyhow::Result<usize> {
+        i.checked_sub(1)
+            .and_then(|i| usize::try_from(i).ok())
+            .ok_or_else(|| anyhow!("indices start at 1, got {i}"))
+    }
+
+    fn field<'r>(&self, reflect: &'r dyn PartialReflect) -> anyhow::Result<&'r dyn PartialReflect> {
+        let type_path = reflect.reflect_type_path();
+        match self.parsed_path(reflect)? {
+            Some(path) => reflect
+                .reflect_path(&path)
+                .map_err(|err| anyhow!("{type_path}: {err}")),
+            None => {
+                let ReflectRef::Map(map) = reflect.reflect_ref() else {
+                    unreachable!()
+                };
+                map.iter()
+                    .find(|(key, _)| self.is_map_key(*key))
+                    .map(|(_, value)| value)
+                    .ok_or_else(|| anyhow!("{type_path} has no key {self}"))
+            }
+        }
+    }
+
+    fn field_mut<'r>(
+        &self,
+        reflect: &'r mut dyn PartialReflect,
+    ) -> anyhow::Result<&'r mut dyn PartialReflect> {
+        let type_path = reflect.reflect_type_path().to_string();
+        match self.parsed_path(reflect)? {
+            Some(path) => reflect
+                .reflect_path_mut(&path)
+                .map_err(|err| anyhow!("{type_path}: {err}")),
+            None => {
+                let ReflectMut::Map(map) = reflect.reflect_mut() else {
+                    unreachable!()
+                };
+                let index = map
+                    .iter()
+                    .position(|(key, _)| self.is_map_key(key))
+                    .ok_or_else(|| anyhow!("{type_path} has no key {self}"))?;
+                Ok(map.get_at_mut(index).unwrap().1)
+            }
+        }
+    }
+
+    fn is_map_key(&self, key: &dyn PartialReflect) -> bool {
+        match self {
+            PathKey::Name(name) => {
+                key.try_downcast_ref::<String>()
+                    .is_some_and(|key| key == name)
+                    || key
+                        .try_downcast_ref::<&'static str>()
+                        .is_some_and(|key| *key == name.as_str())
+                    || key
+                        .try_downcast_ref::<Cow<'static, str>>()
+                        .is_some_and(|key| &**key == name.as_str())
+            }
+            PathKey::Index(i) => {
+                macro_rules! integers {
+                    ($($int:ty),*) => {$(
+                        if let Some(key) = key.try_downcast_ref::<$int>() {
+                            return i64::try_from(*key).is_ok_and(|key| key == *i);
+                        }
+                    )*};
+                }
+                integers!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+                false
+            }
+        }
+    }
+}
+
+impl fmt::Display for PathKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathKey::Name(name) => write!(f, "{name:?}"),
+            PathKey::Index(i) => write!(f, "{i}"),
+        }
     }
 }
 
@@ -178,104 +1052,78 @@ impl UserDataPtr for ReflectPtr {
         metatable
             .set(
                 *ctx,
-                "__add",
+                "__len",
                 Callback::from_fn(ctx, move |ctx, _fuel, mut stack| {
-                    let (this, rhs): (&Self, Value) = stack.consume(ctx)?;
-                    match rhs {
-                        Value::Integer(integer) => {
-                            if let Some(awa) = this.get_field_value_ref().downcast_ref::<i32>() {
-                                stack.push_front(Value::Integer(*awa as i64 + integer))
-                            }
-                        }
-                        Value::Number(number) => {
-                            if let Some(awa) = this.get_field_value_ref().downcast_ref::<f32>() {
-                                stack.push_front(Value::Number(*awa as f64 + number))
-                            }
-                        }
-                        Value::UserData(_) => {
-                            todo!()
-                        }
-                        _ => {
-                            stack.push_front(Value::Nil);
-                            println!("tried to add something that can't be added")
-                        }
-                    }
+                    let this: &Self = stack.consume(ctx)?;
+                    let len = len(this.try_field_value_ref()?.as_partial_reflect())?;
+                    stack.replace(ctx, len as i64);
                     Ok(CallbackReturn::Return)
                 }),
             )
             .unwrap();
+        metatable.set(*ctx, "__pairs", pairs(*ctx)).unwrap();
+        set_operators(*ctx, metatable);
     }
 
     fn lua_to_string(&self) -> String {
-        format!("{:?}", self.get_field_value_ref())
+        match self.try_field_value_ref() {
+            Ok(value) => format!("{value:?}"),
+            Err(err) => format!("<{err}>"),
+        }
     }
 
     // TODO safe mutability by seperating mut vs ref pointers
-    fn lua_index<'gc>(&self, ctx: &Context<'gc>, key: &str) -> Value<'gc> {
-        let mut reflect_ptr = self.clone();
-        if let Some(function_registry) = self
-            .function_registry
-            .borrow()
-            .get(&self.get_field_value_ref().reflect_type_info().type_id())
-        {
-            if let Some(function) = function_registry.get(key) {
-                return lua_wrapped_dynamic_function_call(
+    fn lua_index<'gc>(
+        &self,
+        ctx: &Context<'gc>,
+        key: Value<'gc>,
+    ) -> Result<Value<'gc>, piccolo::Error<'gc>> {
+        let key = PathKey::from_lua(key)?;
+        let reflect = self.try_field_value_ref()?;
+        if let PathKey::Name(name) = &key {
+            let overloads = self
+                .function_registry
+                .borrow()
+                .overloads(reflect.reflect_type_info().type_id(), name);
+            if let Some(overloads) = overloads {
+                return Ok(lua_wrapped_dynamic_function_call(
                     *ctx,
-                    function.clone(),
+                    overloads,
                     self.function_registry.clone(),
-                );
+                ));
+            }
+            let method =
+                collection_method(*ctx, reflect, name).or_else(|| enum_method(*ctx, reflect, name));
+            if let Some(method) = method {
+                return Ok(method.into());
             }
         }
         // this is the case where it's not in the function registry
-        reflect_ptr.path.push('.');
-        reflect_ptr.path.push_str(key);
-        reflect_ptr.into_value(ctx)
+        if key.is_missing_entry(reflect.as_partial_reflect()) {
+            return Ok(Value::Nil);
+        }
+        Ok(self.child(key)?.into_value(ctx))
     }
 
-    fn lua_new_index<'gc>(&self, _ctx: &Context<'gc>, key: &str, new_value: Value<'gc>) {
+    /// Converts the value to the type of the field, so any number fits any int or float field
+    /// it's in range of, and strings fit `String`, `Name` and unit enum variants.
+    fn lua_new_index<'gc>(
+        &self,
+        ctx: &Context<'gc>,
+        key: Value<'gc>,
+        new_value: Value<'gc>,
+    ) -> Result<(), piccolo::Error<'gc>> {
         let mut reflect_ptr = self.clone();
-        reflect_ptr.path.push('.');
-        reflect_ptr.path.push_str(key);
-        match new_value {
-            Value::Number(n) => {
-                let mut reflect_field: &mut dyn Reflect = reflect_ptr.get_field_value_mut();
-                reflect_field.set(Box::new(n as f32)).unwrap();
-            }
-            Value::Integer(i) => {
-                let mut reflect_field: &mut dyn Reflect = reflect_ptr.get_field_value_mut();
-                if let Err(awa) = reflect_field.set(Box::new(i as i32)) {
-                    println!("error setting value: {:?}", awa);
-                }
-            }
-            Value::Boolean(b) => {
-                let mut reflect_field: &mut dyn Reflect = reflect_ptr.get_field_value_mut();
-                reflect_field.set(Box::new(b)).unwrap();
-            }
-            Value::String(_s) => {
-                todo!()
-            }
-            Value::UserData(data) => {
-                if reflect_ptr.path.is_empty() {
-                    reflect_ptr.data = data.downcast_static::<ReflectPtr>().unwrap().data.clone();
-                    reflect_ptr.path = String::default();
-                    reflect_ptr.ptr_state = data
-                        .downcast_static::<ReflectPtr>()
-                        .unwrap()
-                        .ptr_state
-                        .clone();
-                } else {
-                    let reflect_field: &mut dyn Reflect = reflect_ptr.get_field_value_mut();
-                    let reflect = data
-                        .downcast_static::<ReflectPtr>()
-                        .unwrap()
-                        .get_field_value_mut()
-                        .clone_value();
-                    reflect_field.apply(&*reflect);
-                    //reflect_field.set(reflect).unwrap();
-                }
-            }
-            _ => {}
-        }
+        reflect_ptr.path.push(PathKey::from_lua(key)?);
+        let field = reflect_ptr.try_field_value_ref()?;
+        let type_id = field.reflect_type_info().type_id();
+        let type_path = field.reflect_type_path().to_string();
+        let value = lua_to_owned(*ctx, new_value, type_id, &type_path)?;
+        reflect_ptr
+            .try_field_value_mut()?
+            .try_apply(&*value)
+            .map_err(|err| anyhow!("can't set a {type_path}: {err}"))?;
+        Ok(())
     }
 }
 
@@ -286,6 +1134,7 @@ impl Clone for ReflectPtr {
             path: self.path.clone(),
             ptr_state: self.ptr_state.clone(),
             function_registry: self.function_registry.clone(),
+            changed: self.changed.clone(),
         }
     }
 }
@@ -296,6 +1145,116 @@ impl<'gc> FromValue<'gc> for &'gc ReflectPtr {
     }
 }
 
+/// An [`Entity`] handed to lua. The same userdata is reused for an entity every time
+/// it's passed in, so entities can be compared and used as table keys.
+/// They're kept in the `__entities` global until the entity is despawned.
+#[derive(Copy, Clone, Debug)]
+pub struct LuaEntity(pub Entity);
+
+/// Lets go of the `LuaEntity`s of despawned entities, piccolo has no weak tables to do it.
+pub fn forget_despawned_entities(mut lua: NonSendMut<LuaVm>, entities: &Entities) {
+    lua.enter(|ctx| {
+        let Value::Table(cache) = ctx.globals().get::<_, Value>(ctx, "__entities").unwrap() else {
+            return;
+        };
+        let despawned = cache
+            .into_iter()
+            .filter_map(|(key, _)| match key {
+                Value::Integer(bits) => Entity::try_from_bits(bits as u64)
+                    .map_or(true, |entity| !entities.contains(entity))
+                    .then_some(key),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for key in despawned {
+            cache.set(ctx, key, Value::Nil).unwrap();
+        }
+    });
+}
+
+impl<'gc> FromValue<'gc> for &'gc LuaEntity {
+    fn from_value(ctx: Context<'gc>, value: Value<'gc>) -> Result<Self, TypeError> {
+        LuaEntity::from_value_2(ctx, value)
+    }
+}
+
+impl UserDataPtr for LuaEntity {
+    type Data = Entity;
+
+    fn get_data_mut(&self) -> Option<*mut Self::Data> {
+        None
+    }
+
+    fn get_data(&self) -> *const Self::Data {
+        &self.0 as *const Entity
+    }
+
+    fn into_value<'gc>(self, ctx: &Context<'gc>) -> Value<'gc> {
+        let entities = match ctx.globals().get::<_, Value>(*ctx, "__entities").unwrap() {
+            Value::Table(entities) => entities,
+            _ => {
+                let entities = Table::new(ctx);
+                ctx.set_global("__entities", entities);
+                entities
+            }
+        };
+        let key = self.0.to_bits() as i64;
+        if let Value::UserData(user_data) = entities.get::<_, Value>(*ctx, key).unwrap() {
+            return user_data.into();
+        }
+        let metatable = self.metatable(ctx);
+        let user_data = UserData::new_static(ctx, self);
+        user_data.set_metatable(ctx, Some(metatable));
+        entities.set(*ctx, key, user_data).unwrap();
+        user_data.into()
+    }
+
+    fn edit_metatable<'gc>(&self, ctx: &Context<'gc>, metatable: &mut Table<'gc>) {
+        metatable
+            .set(
+                *ctx,
+                "__eq",
+                Callback::from_fn(ctx, move |ctx, _fuel, mut stack| {
+                    let (this, other): (&Self, Value) = stack.consume(ctx)?;
+                    let equal = other
+                        .as_static_user_data::<LuaEntity>()
+                        .is_ok_and(|other| other.0 == this.0);
+                    stack.push_front(Value::Boolean(equal));
+                    Ok(CallbackReturn::Return)
+                }),
+            )
+            .unwrap();
+    }
+
+    fn lua_to_string(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn lua_index<'gc>(
+        &self,
+        _ctx: &Context<'gc>,
+        key: Value<'gc>,
+    ) -> Result<Value<'gc>, piccolo::Error<'gc>> {
+        let Value::String(key) = key else {
+            return Ok(Value::Nil);
+        };
+        Ok(match key.to_str()? {
+            "index" => Value::Integer(self.0.index() as i64),
+            "generation" => Value::Integer(self.0.generation() as i64),
+            &_ => Value::Nil,
+        })
+    }
+
+    fn lua_new_index<'gc>(
+        &self,
+        _ctx: &Context<'gc>,
+        _key: Value<'gc>,
+        _new_value: Value<'gc>,
+    ) -> Result<(), piccolo::Error<'gc>> {
+        Ok(())
+    }
+}
+
 pub struct WorldMut {
     pub(crate) this: Option<*mut World>,
 }
@@ -339,15 +1298,29 @@ impl UserDataPtr for WorldMut {
         "app".to_string()
     }
 
-    fn lua_index<'gc>(&self, ctx: &Context<'gc>, key: &str) -> Value<'gc> {
-        match key {
+    fn lua_index<'gc>(
+        &self,
+        ctx: &Context<'gc>,
+        key: Value<'gc>,
+    ) -> Result<Value<'gc>, piccolo::Error<'gc>> {
+        let Value::String(key) = key else {
+            return Ok(Value::Nil);
+        };
+        Ok(match key.to_str()? {
             "query" => Self::query(ctx).into_value(*ctx),
             "register_system" => Self::register_system(ctx).into_value(*ctx),
             &_ => Value::Nil,
-        }
+        })
     }
 
-    fn lua_new_index<'gc>(&self, _ctx: &Context<'gc>, _key: &str, _new_value: Value<'gc>) {}
+    fn lua_new_index<'gc>(
+        &self,
+        _ctx: &Context<'gc>,
+        _key: Value<'gc>,
+        _new_value: Value<'gc>,
+    ) -> Result<(), piccolo::Error<'gc>> {
+        Ok(())
+    }
 }
 
 impl WorldMut {
@@ -366,67 +1339,86 @@ impl WorldMut {
                 .globals()
                 .get::<_, Value>(ctx, "__systems_vec")
                 .unwrap()
-                .as_static_user_data::<Rc<RefCell<Option<Vec<LuaSystem>>>>>()?;
+                .as_static_user_data::<Rc<RefCell<Option<LuaSystems>>>>()?;
             let systems_vec = systems_vec.clone();
 
-            let (this, system, system_params): (&WorldMut, Value, Table) = stack.consume(ctx)?;
+            let (this, system, system_params, options): (&WorldMut, Value, Table, Option<Table>) =
+                stack.consume(ctx)?;
+            let world = unsafe { &mut *this.get_data_mut().unwrap() };
 
             let function: Function = Function::from_value(ctx, system)?;
 
-            let world = unsafe { &mut *this.get_data_mut().unwrap() };
-
-            let mut system_parameters = vec![];
-
-            for (_, system_parameter) in system_params.into_iter() {
-                //println!("hi");
-                // TODO add resources and other things here too
-                if system_parameter
-                    .as_static_user_data::<CommandQueueMarker>()
-                    .is_ok()
-                {
-                    system_parameters.push(SystemParameter::CommandQueue);
-                    //println!("hello");
-                    continue;
+            let mut schedule = LuaSchedule::Update;
+            let mut name = None;
+            let mut after = vec![];
+            let mut before = vec![];
+            let mut in_sets = vec![];
+            let mut run_if = None;
+            if let Some(options) = options {
+                match options.get::<_, Value>(ctx, "schedule")? {
+                    Value::Nil => {}
+                    Value::String(name) => {
+                        let name = name.to_str()?;
+                        schedule = LuaSchedule::from_name(name).ok_or_else(|| {
+                            anyhow::anyhow!("unknown schedule for lua system: {name}")
+                        })?;
+                    }
+                    _ => return Err(anyhow::anyhow!("schedule must be a string").into()),
                 }
-                if let Ok(resource_component_type) =
-                    system_parameter.as_static_user_data::<ComponentType>()
-                {
-                    system_parameters
-                        .push(SystemParameter::Resource(resource_component_type.clone()));
-                    continue;
+                match options.get::<_, Value>(ctx, "name")? {
+                    Value::Nil => {}
+                    Value::String(system_name) => name = Some(system_name.to_str()?.to_string()),
+                    _ => return Err(anyhow::anyhow!("name must be a string").into()),
                 }
-
-                let table = Table::from_value(ctx, system_parameter)?;
-                //println!("UwU");
-                let mut query_builder = QueryBuilder::<FilteredEntityMut>::new(world);
-                //println!("UwU");
-                //TODO we might want to restrict this to something like mut vs ref components
-                let mut components = vec![];
-                for (_, component_type) in table.into_iter() {
-                    let component_type = UserData::from_value(ctx, component_type)?;
-                    let component_type = component_type
-                        .downcast_static::<ComponentType>()
-                        .unwrap()
-                        .clone();
-                    match component_type {
-                        ComponentType::Ref((component_id, type_id)) => {
-                            query_builder.ref_id(component_id);
-                            components.push(component_type);
-                        }
-                        ComponentType::Mut((component_id, type_id)) => {
-                            query_builder.mut_id(component_id);
-                            components.push(component_type);
-                        }
+                after = system_set_names(options.get(ctx, "after")?)?;
+                before = system_set_names(options.get(ctx, "before")?)?;
+                in_sets = system_set_names(options.get(ctx, "in_set")?)?;
+                match options.get::<_, Value>(ctx, "run_if")? {
+                    Value::Nil => {}
+                    Value::Function(predicate) => {
+                        let system_parameters =
+                            match options.get::<_, Option<Table>>(ctx, "run_if_params")? {
+                                Some(run_if_params) => parse_system_parameters(ctx, run_if_params)?,
+                                None => vec![],
+                            };
+                        check_query_conflicts(world, &system_parameters)?;
+                        run_if = Some(LuaRunCondition::Lua {
+                            lua_func: ctx.stash(predicate),
+                            system_parameters,
+                        });
+                    }
+                    condition => {
+                        run_if = Some(condition.as_static_user_data::<LuaRunCondition>()?.clone());
                     }
                 }
-                let query_state = query_builder.build();
-                system_parameters.push(SystemParameter::Query((query_state, components)));
             }
-            let stashed_function = ctx.stash(function);
-            systems_vec.borrow_mut().as_mut().unwrap().push(LuaSystem {
-                lua_func: stashed_function,
+
+            let system_parameters = parse_system_parameters(ctx, system_params)?;
+            check_query_conflicts(world, &system_parameters)?;
+            let lua_system = LuaSystem {
+                lua_func: ctx.stash(function),
                 system_parameters,
-            });
+                name,
+                after,
+                before,
+                in_sets,
+                run_if,
+            };
+            let mut systems_vec = systems_vec.borrow_mut();
+            let systems = systems_vec.as_mut().unwrap().entry(schedule).or_default();
+            if schedule.label().is_some() {
+                let orderings = systems
+                    .iter()
+                    .chain([&lua_system])
+                    .map(LuaSystem::ordering)
+                    .collect::<Vec<_>>();
+                if let Some(cycle) = find_ordering_cycle(&orderings) {
+                    return Err(
+                        anyhow!("the ordering of this system makes a cycle: {cycle}").into(),
+                    );
+                }
+            }
+            systems.push(lua_system);
             Ok(CallbackReturn::Return)
         })
     }
@@ -451,6 +1443,32 @@ fn register_components_and_markers(world: &mut World) {
                     UserData::new_static(&ctx, CommandQueueMarker).into_value(ctx),
                 )
                 .unwrap();
+            ctx.globals()
+                .set(
+                    ctx,
+                    "Entity",
+                    UserData::new_static(&ctx, EntityMarker).into_value(ctx),
+                )
+                .unwrap();
+            // so rust sets can be written as `before = SystemSets.Gameplay`
+            let system_sets = Table::new(&ctx);
+            if let Some(lua_system_sets) = world.get_resource::<LuaSystemSets>() {
+                for name in lua_system_sets.keys() {
+                    system_sets.set(ctx, name.as_str(), name.as_str()).unwrap();
+                }
+            }
+            ctx.globals().set(ctx, "SystemSets", system_sets).unwrap();
+            ctx.globals()
+                .set(ctx, "Conditions", LuaRunCondition::constructors(&ctx))
+                .unwrap();
+            // for turning the dynamic values `clone_value` makes back into real ones
+            ctx.globals()
+                .set(
+                    ctx,
+                    "__type_registry",
+                    UserData::new_static(&ctx, (*registry).clone()),
+                )
+                .unwrap();
             Ok(CallbackReturn::Return)
         })
         .unwrap();
@@ -520,6 +1538,24 @@ fn register_components_and_markers(world: &mut World) {
                             UserData::new_static(&ctx, ComponentType::Mut((component_id, type_id))),
                         )
                         .unwrap();
+                        t.set(
+                            ctx,
+                            "opt",
+                            UserData::new_static(
+                                &ctx,
+                                ComponentType::OptRef((component_id, type_id)),
+                            ),
+                        )
+                        .unwrap();
+                        t.set(
+                            ctx,
+                            "opt_mut",
+                            UserData::new_static(
+                                &ctx,
+                                ComponentType::OptMut((component_id, type_id)),
+                            ),
+                        )
+                        .unwrap();
 
                         break;
                     }
@@ -607,6 +1643,526 @@ fn register_components_and_markers(world: &mut World) {
             })
             .unwrap();
         }
+        // `Transform{ translation = glam.Vec3{x = 1} }` style constructors, `Transform.default()`
+        // for everything with `ReflectDefault` and `Visibility.Hidden` style enum variants
+        world.init_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>();
+        let object_function_registry = world
+            .non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>()
+            .clone();
+        for item in registry.read().iter() {
+            let type_path = item.type_info().type_path();
+            let constructible = is_constructible(item);
+            let has_default = item.data::<ReflectDefault>().is_some();
+            let is_enum = matches!(item.type_info(), TypeInfo::Enum(_));
+            if !(constructible || has_default || is_enum) || type_path.contains('<') {
+                continue;
+            }
+            let type_id = item.type_id();
+            lua.try_enter(|ctx| {
+                let Some(t) = namespace_table(ctx, type_path) else {
+                    return Ok(());
+                };
+                if constructible {
+                    let metatable = t.metatable().unwrap_or_else(|| Table::new(&ctx));
+                    metatable
+                        .set(
+                            ctx,
+                            "__call",
+                            constructor(
+                                ctx,
+                                type_id,
+                                (*registry).clone(),
+                                object_function_registry.clone(),
+                            ),
+                        )
+                        .unwrap();
+                    t.set_metatable(&ctx, Some(metatable));
+                }
+                if is_enum {
+                    let metatable = t.metatable().unwrap_or_else(|| Table::new(&ctx));
+                    metatable
+                        .set(
+                            ctx,
+                            "__index",
+                            variant_index(
+                                ctx,
+                                type_id,
+                                (*registry).clone(),
+                                object_function_registry.clone(),
+                            ),
+                        )
+                        .unwrap();
+                    t.set_metatable(&ctx, Some(metatable));
+                }
+                // a hand registered `default` wins
+                if has_default && matches!(t.get::<_, Value>(ctx, "default")?, Value::Nil) {
+                    t.set(
+                        ctx,
+                        "default",
+                        default_constructor(
+                            ctx,
+                            type_id,
+                            (*registry).clone(),
+                            object_function_registry.clone(),
+                        ),
+                    )
+                    .unwrap();
+                }
+                Ok(())
+            })
+            .unwrap();
+        }
+        // everything registered with `app.register_function`, under its path and in `fn`
+        if let Some(function_registry) = world.get_resource::<AppFunctionRegistry>() {
+            for function in function_registry.read().iter() {
+                let Some(name) = function.name() else {
+                    continue;
+                };
+                let name = name.to_string();
+                lua.try_enter(|ctx| {
+                    let wrapped = lua_wrapped_dynamic_function_call(
+                        ctx,
+                        vec![function.clone()],
+                        object_function_registry.clone(),
+                    );
+                    let flat = match ctx.globals().get::<_, Value>(ctx, "fn")? {
+                        Value::Table(flat) => flat,
+                        _ => {
+                            let flat = Table::new(&ctx);
+                            ctx.globals().set(ctx, "fn", flat).unwrap();
+                            flat
+                        }
+                    };
+                    flat.set(ctx, name.as_str(), wrapped).unwrap();
+
+                    // closures and generic functions have names that aren't paths
+                    if name.contains(['<', '{', ' ']) {
+                        return Ok(());
+                    }
+                    let Some((path, function_name)) = name.rsplit_once("::") else {
+                        return Ok(());
+                    };
+                    // `fn.add` as well as `fn["my_crate::add"]`, unless two paths end the same
+                    if matches!(flat.get::<_, Value>(ctx, function_name)?, Value::Nil) {
+                        flat.set(ctx, function_name, wrapped).unwrap();
+                    }
+                    let Some(t) = namespace_table(ctx, path) else {
+                        return Ok(());
+                    };
+                    if matches!(t.get::<_, Value>(ctx, function_name)?, Value::Nil) {
+                        t.set(ctx, function_name, wrapped).unwrap();
+                    }
+                    Ok(())
+                })
+                .unwrap();
+            }
+        }
         world.insert_non_send_resource(lua);
     });
 }
+
+/// Finds or creates the table at `type_path` in the globals, `None` if something
+/// that isn't a table is already in the way.
+fn namespace_table<'gc>(ctx: Context<'gc>, type_path: &str) -> Option<Table<'gc>> {
+    let mut lua_table = ctx.globals();
+    for item in type_path.split("::") {
+        lua_table = match lua_table.get::<_, Value>(ctx, item).unwrap() {
+            Value::Nil => {
+                let table = Table::new(&ctx);
+                lua_table.set(ctx, item, table).unwrap();
+                table
+            }
+            Value::Table(table) => table,
+            _ => return None,
+        };
+    }
+    Some(lua_table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::reflect::FromType;
+    use piccolo::{Closure, Executor, Lua};
+
+    #[derive(Reflect)]
+    struct Point {
+        x: f32,
+        y: f32,
+    }
+
+    #[test]
+    fn path_keys_from_lua_numbers() {
+        assert!(matches!(
+            PathKey::from_lua(Value::Integer(2)),
+            Ok(PathKey::Index(2))
+        ));
+        assert!(matches!(
+            PathKey::from_lua(Value::Number(2.0)),
+            Ok(PathKey::Index(2))
+        ));
+        assert!(PathKey::from_lua(Value::Number(2.5)).is_err());
+        assert!(PathKey::from_lua(Value::Nil).is_err());
+    }
+
+    #[test]
+    fn indices_start_at_one() {
+        let list = vec![10, 20, 30];
+        let first = PathKey::Index(1).field(&list).unwrap();
+        assert_eq!(first.try_downcast_ref::<i32>(), Some(&10));
+        let last = PathKey::Index(3).field(&list).unwrap();
+        assert_eq!(last.try_downcast_ref::<i32>(), Some(&30));
+        assert!(PathKey::Index(0).field(&list).is_err());
+        assert!(PathKey::Index(4).field(&list).is_err());
+        assert!(PathKey::Index(i64::MIN).field(&list).is_err());
+
+        let tuple = (1u8, "two".to_string());
+        let second = PathKey::Index(2).field(&tuple).unwrap();
+        assert_eq!(
+            second.try_downcast_ref::<String>(),
+            Some(&"two".to_string())
+        );
+    }
+
+    #[test]
+    fn struct_fields_by_name_or_position() {
+        let point = Point { x: 1.0, y: 2.0 };
+        let y = PathKey::Name("y".to_string()).field(&point).unwrap();
+        assert_eq!(y.try_downcast_ref::<f32>(), Some(&2.0));
+        let x = PathKey::Index(1).field(&point).unwrap();
+        assert_eq!(x.try_downcast_ref::<f32>(), Some(&1.0));
+        assert!(PathKey::Name("z".to_string()).field(&point).is_err());
+
+        let mut point = point;
+        let x = PathKey::Name("x".to_string())
+            .field_mut(&mut point)
+            .unwrap();
+        x.apply(&5.0f32);
+        assert_eq!(point.x, 5.0);
+    }
+
+    #[test]
+    fn map_keys() {
+        let mut by_name = HashMap::new();
+        by_name.insert("a".to_string(), 1);
+        let a = PathKey::Name("a".to_string()).field(&by_name).unwrap();
+        assert_eq!(a.try_downcast_ref::<i32>(), Some(&1));
+        assert!(PathKey::Name("b".to_string()).field(&by_name).is_err());
+
+        let mut by_number = HashMap::new();
+        by_number.insert(7u8, "seven".to_string());
+        assert!(PathKey::Index(7).field(&by_number).is_ok());
+        assert!(PathKey::Index(-1).field(&by_number).is_err());
+        assert!(matches!(
+            PathKey::from_reflect(&7u8),
+            Some(PathKey::Index(7))
+        ));
+        assert!(PathKey::from_reflect(&u64::MAX).is_none());
+    }
+
+    #[test]
+    fn missing_entries_are_nil() {
+        let list = vec![1, 2];
+        assert!(!PathKey::Index(2).is_missing_entry(&list));
+        assert!(PathKey::Index(3).is_missing_entry(&list));
+        assert!(PathKey::Index(0).is_missing_entry(&list));
+        let mut map = HashMap::new();
+        map.insert("a".to_string(), 1);
+        assert!(!PathKey::Name("a".to_string()).is_missing_entry(&map));
+        assert!(PathKey::Name("b".to_string()).is_missing_entry(&map));
+        // a missing struct field is a mistake rather than an empty slot
+        let point = Point { x: 0.0, y: 0.0 };
+        assert!(!PathKey::Name("z".to_string()).is_missing_entry(&point));
+    }
+
+    #[test]
+    fn path_keys_display_like_lua() {
+        assert_eq!(PathKey::Name("x".to_string()).to_string(), "\"x\"");
+        assert_eq!(PathKey::Index(3).to_string(), "3");
+    }
+
+    fn ordering(name: &str, after: &[&str], before: &[&str]) -> LuaOrdering {
+        LuaOrdering {
+            name: Some(name.to_string()),
+            sets: vec![name.to_string()],
+            after: after.iter().map(|set| set.to_string()).collect(),
+            before: before.iter().map(|set| set.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn ordering_cycles() {
+        assert_eq!(
+            find_ordering_cycle(&[ordering("a", &["a"], &[])]).as_deref(),
+            Some("\"a\" runs before \"a\"")
+        );
+        assert_eq!(
+            find_ordering_cycle(&[ordering("a", &[], &["b"]), ordering("b", &[], &["a"])])
+                .as_deref(),
+            Some("\"a\" runs before \"b\" runs before \"a\"")
+        );
+        // b after a and a after b, through `after` on both
+        assert!(
+            find_ordering_cycle(&[ordering("a", &["b"], &[]), ordering("b", &["a"], &[])])
+                .is_some()
+        );
+        assert!(find_ordering_cycle(&[
+            ordering("a", &[], &["b"]),
+            ordering("b", &[], &["c"]),
+            ordering("c", &[], &["a"]),
+        ])
+        .is_some());
+    }
+
+    #[test]
+    fn orderings_without_cycles() {
+        assert!(find_ordering_cycle(&[]).is_none());
+        assert!(find_ordering_cycle(&[
+            ordering("a", &[], &["b"]),
+            ordering("b", &["a"], &["c"]),
+            ordering("c", &["a"], &[]),
+        ])
+        .is_none());
+        // sets nothing defines don't order anything
+        assert!(find_ordering_cycle(&[ordering("a", &["x"], &["x"])]).is_none());
+    }
+
+    fn query(terms: &[ComponentType]) -> LuaQuery {
+        LuaQuery {
+            terms: terms.iter().copied().map(QueryTerm::Component).collect(),
+            ..default()
+        }
+    }
+
+    #[test]
+    fn conflicting_queries() {
+        let mut world = World::new();
+        let transform = (
+            world.register_component::<Transform>(),
+            TypeId::of::<Transform>(),
+        );
+        let name = (world.register_component::<Name>(), TypeId::of::<Name>());
+
+        let writes = query(&[ComponentType::Mut(transform)]);
+        let reads = query(&[ComponentType::Ref(transform), ComponentType::Ref(name)]);
+        assert_eq!(writes.conflicts(&reads), vec![transform.0]);
+        let params = [
+            SystemParameter::Query(writes.clone()),
+            SystemParameter::Query(reads.clone()),
+        ];
+        assert!(check_query_conflicts(&mut world, &params).is_err());
+
+        // both only reading is fine
+        let both_read = [
+            SystemParameter::Query(reads.clone()),
+            SystemParameter::Query(reads.clone()),
+        ];
+        assert!(check_query_conflicts(&mut world, &both_read).is_ok());
+
+        // and so is keeping them apart
+        let apart = [
+            SystemParameter::Query(LuaQuery {
+                without: vec![name.0],
+                ..writes
+            }),
+            SystemParameter::Query(reads),
+        ];
+        assert!(check_query_conflicts(&mut world, &apart).is_ok());
+    }
+
+    #[test]
+    fn changed_filters_read() {
+        let mut world = World::new();
+        let transform = (
+            world.register_component::<Transform>(),
+            TypeId::of::<Transform>(),
+        );
+        let writes = query(&[ComponentType::Mut(transform)]);
+        let changed = LuaQuery {
+            changed: vec![transform.0],
+            ..default()
+        };
+        assert_eq!(writes.conflicts(&changed), vec![transform.0]);
+    }
+
+    #[test]
+    fn run_condition_shapes() {
+        let every_2 = LuaRunCondition::EveryNFrames(2);
+        assert!(every_2.same_shape(&LuaRunCondition::EveryNFrames(2)));
+        assert!(!every_2.same_shape(&LuaRunCondition::EveryNFrames(3)));
+        assert!(!every_2.same_shape(&LuaRunCondition::OnTimer(2.0)));
+        assert!(LuaRunCondition::OnTimer(0.5).same_shape(&LuaRunCondition::OnTimer(0.5)));
+    }
+
+    fn valid() -> Rc<RefCell<PtrState>> {
+        Rc::new(RefCell::new(PtrState::Valid))
+    }
+
+    #[test]
+    fn moved_boxed_values_error() {
+        let boxed = ReflectPtr::new_boxed(Box::new(1.0f32), valid(), Rc::default());
+        let copy = boxed.clone();
+        assert!(boxed.take().is_ok());
+        assert!(copy.try_field_value_ref().is_err());
+        assert!(copy.try_field_value_mut().is_err());
+        assert!(copy.take().is_err());
+        assert_eq!(
+            copy.lua_to_string(),
+            "<value was moved into a function call>"
+        );
+    }
+
+    #[test]
+    fn pointers_error_after_their_system() {
+        let mut value = 1.0f32;
+        let ptr_state = valid();
+        let ptr = ReflectPtr::new_mut(&mut value, ptr_state.clone(), Rc::default());
+        assert!(ptr.try_field_value_ref().is_ok());
+        *ptr_state.borrow_mut() = PtrState::Invalid;
+        assert!(ptr.try_field_value_ref().is_err());
+        assert!(ptr.try_field_value_mut().is_err());
+    }
+
+    #[derive(Resource, Reflect)]
+    struct Score(i32);
+
+    #[test]
+    fn only_writes_mark_changed() {
+        let mut world = World::new();
+        world.insert_resource(Score(0));
+        let component_id = world.resource_id::<Score>().unwrap();
+        let reflect_from_ptr = <ReflectFromPtr as FromType<Score>>::from_type();
+        world.clear_trackers();
+
+        let value = world.get_resource_mut_by_id(component_id).unwrap();
+        let ptr =
+            unsafe { ReflectPtr::new_tracked(value, &reflect_from_ptr, valid(), Rc::default()) };
+        let score = ptr.child(PathKey::Index(1)).unwrap();
+        score.try_field_value_ref().unwrap();
+        assert!(!world.is_resource_changed::<Score>());
+
+        score.try_field_value_mut().unwrap().apply(&5);
+        assert!(world.is_resource_changed::<Score>());
+        assert_eq!(world.resource::<Score>().0, 5);
+    }
+
+    #[test]
+    fn metatables_are_built_once_per_type() {
+        Lua::core().enter(|ctx| {
+            let metatable = |value: Value| match value {
+                Value::UserData(user_data) => user_data.metatable().unwrap(),
+                _ => panic!("expected userdata"),
+            };
+            let a = ReflectPtr::new_boxed(Box::new(1.0f32), valid(), Rc::default());
+            let b = ReflectPtr::new_boxed(Box::new(Vec3::ZERO), valid(), Rc::default());
+            let a = metatable(a.into_value(&ctx));
+            let b = metatable(b.into_value(&ctx));
+            assert!(a == b);
+            let entity = metatable(LuaEntity(Entity::PLACEHOLDER).into_value(&ctx));
+            assert!(a != entity);
+        });
+    }
+
+    #[test]
+    fn query_parameters() {
+        Lua::core().enter(|ctx| {
+            let ids = (ComponentId::new(0), TypeId::of::<Transform>());
+            let term = |component_type: ComponentType| -> Value {
+                UserData::new_static(&ctx, component_type).into()
+            };
+            let params = |terms: &[Value]| {
+                let query = Table::new(&ctx);
+                for (i, value) in terms.iter().enumerate() {
+                    query.set(ctx, i as i64 + 1, *value).unwrap();
+                }
+                let params = Table::new(&ctx);
+                params.set(ctx, 1, query).unwrap();
+                (params, query)
+            };
+            let entity: Value = UserData::new_static(&ctx, EntityMarker).into();
+
+            let (ok, _) = params(&[entity, term(ComponentType::OptRef(ids))]);
+            assert!(parse_system_parameters(ctx, ok).is_ok());
+            let (leading_optional, _) = params(&[
+                term(ComponentType::OptMut(ids)),
+                term(ComponentType::Ref(ids)),
+            ]);
+            assert!(parse_system_parameters(ctx, leading_optional).is_err());
+
+            let (filtered, query) = params(&[term(ComponentType::Mut(ids))]);
+            let without = Table::new(&ctx);
+            without.set(ctx, 1, term(ComponentType::Ref(ids))).unwrap();
+            query.set(ctx, "without", without).unwrap();
+            let Ok(parsed) = parse_system_parameters(ctx, filtered) else {
+                panic!("expected the query to parse");
+            };
+            assert!(
+                parsed
+                    == vec![SystemParameter::Query(LuaQuery {
+                        terms: vec![QueryTerm::Component(ComponentType::Mut(ids))],
+                        without: vec![ids.0],
+                        ..default()
+                    })]
+            );
+
+            query.set(ctx, "sometimes", Table::new(&ctx)).unwrap();
+            let unknown = Table::new(&ctx);
+            unknown.set(ctx, 1, query).unwrap();
+            assert!(parse_system_parameters(ctx, unknown).is_err());
+        });
+    }
+
+    fn runs(source: &'static str) -> bool {
+        let mut lua = Lua::core();
+        let exec = lua
+            .try_enter(|ctx| {
+                ctx.set_global("Conditions", LuaRunCondition::constructors(&ctx));
+                let closure = Closure::load(ctx, None, source.as_bytes())?;
+                Ok(ctx.stash(Executor::start(ctx, closure.into(), ())))
+            })
+            .unwrap();
+        lua.execute::<()>(&exec).is_ok()
+    }
+
+    #[test]
+    fn run_condition_arguments() {
+        assert!(runs("Conditions.every_n_frames(3)"));
+        assert!(!runs("Conditions.every_n_frames(0)"));
+        assert!(!runs("Conditions.every_n_frames(-1)"));
+        assert!(!runs("Conditions.every_n_frames(5000000000)"));
+        assert!(runs("Conditions.on_timer(0)"));
+        assert!(runs("Conditions.on_timer(2.5)"));
+        assert!(!runs("Conditions.on_timer(-1)"));
+        assert!(!runs("Conditions.on_timer(0/0)"));
+        assert!(!runs("Conditions.on_timer(1e300)"));
+    }
+
+    #[test]
+    fn despawned_entities_are_forgotten() {
+        let mut world = World::new();
+        let kept = world.spawn_empty().id();
+        let despawned = world.spawn_empty().id();
+        let mut lua = LuaVm::default();
+        lua.enter(|ctx| {
+            LuaEntity(kept).into_value(&ctx);
+            LuaEntity(despawned).into_value(&ctx);
+        });
+        world.insert_non_send_resource(lua);
+        world.despawn(despawned);
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(forget_despawned_entities);
+        schedule.run(&mut world);
+
+        let mut lua = world.remove_non_send_resource::<LuaVm>().unwrap();
+        lua.enter(|ctx| {
+            let cache: Table = ctx.globals().get(ctx, "__entities").unwrap();
+            let cached = |entity: Entity| {
+                let value: Value = cache.get(ctx, entity.to_bits() as i64).unwrap();
+                !matches!(value, Value::Nil)
+            };
+            assert!(cached(kept));
+            assert!(!cached(despawned));
+        });
+    }
+}
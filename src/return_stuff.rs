@@ -0,0 +1,250 @@
+use crate::construct_stuff::type_registry;
+use crate::reflect_stuff::{LuaEntity, ObjectFunctionRegistry, PtrState, ReflectPtr};
+use crate::userdata_stuff::UserDataPtr;
+use anyhow::anyhow;
+use bevy::prelude::*;
+use bevy::reflect::func::Return;
+use bevy::reflect::{ReflectFromReflect, ReflectMut, ReflectRef};
+use piccolo::{Context, Table, Value};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Turns what a bound rust function returned into the values the lua call returns.
+/// References come back as `ReflectPtr`s sharing `borrowed_ptr_state`, the `ptr_state` of the
+/// `ReflectPtr`s they were borrowed from, so they are invalidated along with them. Without one
+/// they could point into a converted argument or a boxed value, so they're copied into boxed
+/// values instead.
+pub fn return_to_lua<'gc>(
+    ctx: Context<'gc>,
+    ret: Return,
+    borrowed_ptr_state: Option<Rc<RefCell<PtrState>>>,
+    object_function_registry: &Rc<RefCell<ObjectFunctionRegistry>>,
+) -> Result<Vec<Value<'gc>>, piccolo::Error<'gc>> {
+    if ret.is_unit() {
+        return Ok(vec![]);
+    }
+    match (ret, borrowed_ptr_state) {
+        (Return::Owned(owned), _) => {
+            // `(A, B)` comes back as multiple values
+            if let ReflectRef::Tuple(tuple) = owned.reflect_ref() {
+                return tuple
+                    .iter_fields()
+                    .map(|field| {
+                        owned_to_lua(
+                            ctx,
+                            concrete(ctx, field.clone_value()),
+                            object_function_registry,
+                        )
+                    })
+                    .collect();
+            }
+            Ok(vec![owned_to_lua(ctx, owned, object_function_registry)?])
+        }
+        (Return::Ref(reference), None) => {
+            let owned = concrete(ctx, reference.clone_value());
+            Ok(vec![owned_to_lua(ctx, owned, object_function_registry)?])
+        }
+        (Return::Mut(reference), None) => {
+            let owned = concrete(ctx, reference.clone_value());
+            Ok(vec![owned_to_lua(ctx, owned, object_function_registry)?])
+        }
+        (Return::Ref(reference), Some(ptr_state)) => {
+            if let Some(value) = primitive_to_lua(ctx, reference) {
+                return Ok(vec![value]);
+            }
+            let reference = reference.try_as_reflect().ok_or_else(|| {
+                anyhow!(
+                    "{} isn't a full Reflect type",
+                    reference.reflect_type_path()
+                )
+            })?;
+            Ok(vec![ReflectPtr::new_ref(
+                reference,
+                ptr_state,
+                object_function_registry.clone(),
+            )
+            .into_value(&ctx)])
+        }
+        (Return::Mut(reference), Some(ptr_state)) => {
+            if let Some(value) = primitive_to_lua(ctx, reference) {
+                return Ok(vec![value]);
+            }
+            let type_path = reference.reflect_type_path().to_string();
+            let reference = reference
+                .try_as_reflect_mut()
+                .ok_or_else(|| anyhow!("{type_path} isn't a full Reflect type"))?;
+            Ok(vec![ReflectPtr::new_mut(
+                reference,
+                ptr_state,
+                object_function_registry.clone(),
+            )
+            .into_value(&ctx)])
+        }
+    }
+}
+
+/// Primitives, strings and `Entity` become plain lua values, `Option` becomes the value or
+/// `nil`, `Result::Err` raises a lua error, lists and maps become tables and everything else
+/// a boxed `ReflectPtr`.
+pub fn owned_to_lua<'gc>(
+    ctx: Context<'gc>,
+    value: Box<dyn PartialReflect>,
+    object_function_registry: &Rc<RefCell<ObjectFunctionRegistry>>,
+) -> Result<Value<'gc>, piccolo::Error<'gc>> {
+    if let Some(value) = primitive_to_lua(ctx, &*value) {
+        return Ok(value);
+    }
+    let type_path = value.reflect_type_path().to_string();
+    match value.reflect_mut() {
+        ReflectMut::List(list) => {
+            return sequence_to_lua(ctx, list.drain(), object_function_registry);
+        }
+        ReflectMut::Array(array) => {
+            let items = array
+                .iter()
+                .map(|item| concrete(ctx, item.clone_value()))
+                .collect();
+            return sequence_to_lua(ctx, items, object_function_registry);
+        }
+        ReflectMut::Map(map) => {
+            let table = Table::new(&ctx);
+            for (key, value) in map.drain() {
+                let key = owned_to_lua(ctx, key, object_function_registry)?;
+                let value = owned_to_lua(ctx, value, object_function_registry)?;
+                table.set(ctx, key, value).map_err(anyhow::Error::from)?;
+            }
+            return Ok(table.into());
+        }
+        ReflectMut::Enum(e) if type_path.starts_with("core::option::Option<") => {
+            return match e.field_at(0) {
+                Some(some) => owned_to_lua(
+                    ctx,
+                    concrete(ctx, some.clone_value()),
+                    object_function_registry,
+                ),
+                None => Ok(Value::Nil),
+            };
+        }
+        ReflectMut::Enum(e) if type_path.starts_with("core::result::Result<") => {
+            let payload = e
+                .field_at(0)
+                .map(|payload| concrete(ctx, payload.clone_value()));
+            return match (e.variant_name(), payload) {
+                ("Ok", Some(ok)) if ok.represents::<()>() => Ok(Value::Nil),
+                ("Ok", Some(ok)) => owned_to_lua(ctx, ok, object_function_registry),
+                ("Ok", None) => Ok(Value::Nil),
+                (_, Some(err)) => match err.try_downcast_ref::<String>() {
+                    Some(err) => Err(anyhow!("{err}").into()),
+                    None => Err(anyhow!("{err:?}").into()),
+                },
+                (_, None) => Err(anyhow!("{type_path} returned an error").into()),
+            };
+        }
+        _ => {}
+    }
+    let value = concrete(ctx, value)
+        .try_into_reflect()
+        .map_err(|value| anyhow!("{} isn't a full Reflect type", value.reflect_type_path()))?;
+    Ok(ReflectPtr::new_boxed(
+        value,
+        Rc::new(RefCell::new(PtrState::Valid)),
+        object_function_registry.clone(),
+    )
+    .into_value(&ctx))
+}
+
+fn sequence_to_lua<'gc>(
+    ctx: Context<'gc>,
+    items: Vec<Box<dyn PartialReflect>>,
+    object_function_registry: &Rc<RefCell<ObjectFunctionRegistry>>,
+) -> Result<Value<'gc>, piccolo::Error<'gc>> {
+    let table = Table::new(&ctx);
+    for (i, item) in items.into_iter().enumerate() {
+        let item = owned_to_lua(ctx, item, object_function_registry)?;
+        table
+            .set(ctx, i as i64 + 1, item)
+            .map_err(anyhow::Error::from)?;
+    }
+    Ok(table.into())
+}
+
+pub fn primitive_to_lua<'gc>(ctx: Context<'gc>, value: &dyn PartialReflect) -> Option<Value<'gc>> {
+    macro_rules! integers {
+        ($($int:ty),*) => {$(
+            if let Some(integer) = value.try_downcast_ref::<$int>() {
+                // lua integers are i64, bigger u64s and usizes become floats instead of wrapping
+                return Some(match i64::try_from(*integer) {
+                    Ok(integer) => Value::Integer(integer),
+                    Err(_) => Value::Number(*integer as f64),
+                });
+            }
+        )*};
+    }
+    integers!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+    if let Some(float) = value.try_downcast_ref::<f32>() {
+        return Some(Value::Number(*float as f64));
+    }
+    if let Some(float) = value.try_downcast_ref::<f64>() {
+        return Some(Value::Number(*float));
+    }
+    if let Some(b) = value.try_downcast_ref::<bool>() {
+        return Some(Value::Boolean(*b));
+    }
+    let string = if let Some(s) = value.try_downcast_ref::<String>() {
+        Some(s.as_str())
+    } else if let Some(s) = value.try_downcast_ref::<&'static str>() {
+        Some(*s)
+    } else {
+        value.try_downcast_ref::<Cow<'static, str>>().map(|s| &**s)
+    };
+    if let Some(s) = string {
+        return Some(Value::String(piccolo::String::from_slice(&ctx, s)));
+    }
+    if let Some(entity) = value.try_downcast_ref::<Entity>() {
+        return Some(LuaEntity(*entity).into_value(&ctx));
+    }
+    None
+}
+
+/// `clone_value` hands back dynamic types for structs and enums, this turns them back into
+/// the real type so functions registered for it can be found.
+pub fn concrete(ctx: Context, value: Box<dyn PartialReflect>) -> Box<dyn PartialReflect> {
+    if !value.is_dynamic() {
+        return value;
+    }
+    let Some(registry) = type_registry(ctx) else {
+        return value;
+    };
+    let Some(type_info) = value.get_represented_type_info() else {
+        return value;
+    };
+    let registry = registry.read();
+    match registry
+        .get_type_data::<ReflectFromReflect>(type_info.type_id())
+        .and_then(|from_reflect| from_reflect.from_reflect(&*value))
+    {
+        Some(concrete) => concrete.into_partial_reflect(),
+        None => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use piccolo::Lua;
+
+    #[test]
+    fn big_unsigned_integers_stay_positive() {
+        Lua::core().enter(|ctx| {
+            let value = primitive_to_lua(ctx, &u64::MAX).unwrap();
+            assert!(matches!(value, Value::Number(float) if float == u64::MAX as f64));
+            let value = primitive_to_lua(ctx, &(i64::MAX as usize + 1)).unwrap();
+            assert!(matches!(value, Value::Number(float) if float > 0.0));
+            let value = primitive_to_lua(ctx, &(i64::MAX as u64)).unwrap();
+            assert!(matches!(value, Value::Integer(i64::MAX)));
+            let value = primitive_to_lua(ctx, &-3i8).unwrap();
+            assert!(matches!(value, Value::Integer(-3)));
+        });
+    }
+}
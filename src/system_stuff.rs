@@ -0,0 +1,633 @@
+use crate::asset_loader::LuaScript;
+use crate::reflect_stuff::{
+    find_ordering_cycle, ComponentType, LuaOrdering, LuaRunCondition, LuaSchedule, LuaSystem,
+    LuaSystemSet, LuaSystemSets, ObjectFunctionRegistry, PtrState, ReflectPtr, SystemParameter,
+};
+use crate::userdata_stuff::UserDataPtr;
+use crate::{CommandQueueWrapper, LuaVm, QueryRows};
+use bevy::ecs::component::ComponentId;
+use bevy::ecs::entity::Entities;
+use bevy::ecs::event::EventCursor;
+use bevy::ecs::system::{
+    BoxedSystem, Deferred, FilteredResourcesMutParamBuilder, ParamBuilder, QueryParamBuilder,
+    SystemChangeTick, SystemParamBuilder,
+};
+use bevy::ecs::world::{FilteredEntityMut, FilteredResourcesMut, FilteredResourcesMutBuilder};
+use bevy::prelude::*;
+use bevy::reflect::{ReflectFromPtr, ReflectRef};
+use bevy::time::common_conditions::on_timer;
+use piccolo::{Executor, IntoValue, StashedFunction, Value, Variadic};
+use send_wrapper::SendWrapper;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The lua function a system runs. Bevy can't take systems back out of a schedule, so
+/// reloading a script swaps the function in here and unloading it empties it.
+type LuaFunctionSlot = Rc<RefCell<Option<StashedFunction>>>;
+
+/// A system of a loaded script that's in a schedule.
+struct InstalledLuaSystem {
+    schedule: LuaSchedule,
+    lua_system: LuaSystem,
+    lua_func: LuaFunctionSlot,
+    /// The slot of a lua `run_if`, which runs as its own system.
+    predicate: Option<LuaFunctionSlot>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl InstalledLuaSystem {
+    /// Runs the functions of `lua_system` from now on, it has to have the same shape.
+    fn reload(&mut self, lua_system: LuaSystem) {
+        *self.lua_func.borrow_mut() = Some(lua_system.lua_func.clone());
+        if let (Some(predicate), Some(LuaRunCondition::Lua { lua_func, .. })) =
+            (&self.predicate, &lua_system.run_if)
+        {
+            *predicate.borrow_mut() = Some(lua_func.clone());
+        }
+        self.lua_system = lua_system;
+    }
+
+    /// Stops the system for good, its run condition keeps it from running and the lua
+    /// functions are let go of.
+    fn disable(self) {
+        self.enabled.store(false, Ordering::Relaxed);
+        *self.lua_func.borrow_mut() = None;
+        if let Some(predicate) = self.predicate {
+            *predicate.borrow_mut() = None;
+        }
+    }
+}
+
+/// Keeps track of the systems each loaded script has in the schedules. A reloaded script
+/// keeps the systems that only changed their lua function, the others get disabled and
+/// new ones are added.
+#[derive(Default)]
+pub struct LuaScriptSystems {
+    scripts: HashMap<AssetId<LuaScript>, Vec<InstalledLuaSystem>>,
+    /// Disabled systems are still in their schedule with their ordering, so they're kept
+    /// around for finding ordering cycles.
+    retired: Vec<(LuaSchedule, LuaOrdering)>,
+}
+
+impl LuaScriptSystems {
+    fn retire(&mut self, system: InstalledLuaSystem) {
+        self.retired
+            .push((system.schedule, system.lua_system.ordering()));
+        system.disable();
+    }
+
+    /// The ordering cycle the schedules would have with `added` in them as well.
+    fn cycle_with(
+        &self,
+        added: impl IntoIterator<Item = (LuaSchedule, LuaOrdering)>,
+    ) -> Option<String> {
+        let installed = self
+            .scripts
+            .values()
+            .flatten()
+            .map(|system| (system.schedule, system.lua_system.ordering()));
+        let mut schedules = HashMap::<_, Vec<_>>::new();
+        for (schedule, ordering) in installed.chain(self.retired.iter().cloned()).chain(added) {
+            // startup systems run on their own, they aren't ordered
+            if schedule.label().is_some() {
+                schedules.entry(schedule).or_default().push(ordering);
+            }
+        }
+        schedules
+            .values()
+            .find_map(|orderings| find_ordering_cycle(orderings))
+    }
+}
+
+/// Everything the lua side of a system needs, this only ever gets touched on the main thread
+/// because the system takes the [`LuaVm`] as a `NonSendMut`.
+struct LuaSystemState {
+    lua_func: LuaFunctionSlot,
+    system_parameters: Vec<SystemParameter>,
+    object_function_registry: Rc<RefCell<ObjectFunctionRegistry>>,
+    /// Set for run condition systems, which store what the lua function returned here.
+    passed: Option<Arc<AtomicBool>>,
+}
+
+pub fn install_lua_systems(
+    world: &mut World,
+    mut asset_events: Local<EventCursor<AssetEvent<LuaScript>>>,
+) {
+    let events = asset_events
+        .read(world.resource::<Events<AssetEvent<LuaScript>>>())
+        .cloned()
+        .collect::<Vec<_>>();
+    if events.is_empty() {
+        return;
+    }
+    world.init_non_send_resource::<LuaScriptSystems>();
+    let known_sets = known_set_names(world);
+    for event in events {
+        match event {
+            AssetEvent::Added { id } | AssetEvent::Modified { id } => {
+                let old = world
+                    .non_send_resource_mut::<LuaScriptSystems>()
+                    .scripts
+                    .remove(&id)
+                    .unwrap_or_default();
+                let Some(lua_script) = world.resource::<Assets<LuaScript>>().get(id) else {
+                    let mut script_systems = world.non_send_resource_mut::<LuaScriptSystems>();
+                    old.into_iter()
+                        .for_each(|system| script_systems.retire(system));
+                    continue;
+                };
+                let lua_systems = (*lua_script.systems).clone();
+
+                // the old system each new one takes over, if it has the same shape
+                let mut taken = vec![false; old.len()];
+                let mut plan = vec![];
+                for (schedule, lua_systems) in lua_systems {
+                    for lua_system in lua_systems {
+                        for name in lua_system.after.iter().chain(&lua_system.before) {
+                            if !known_sets.contains(name) {
+                                warn!(
+                                    "a lua system is ordered against {name:?}, which is neither \
+                                    a lua system nor a set exposed with `expose_system_set_to_lua`"
+                                );
+                            }
+                        }
+                        let same = (0..old.len()).find(|i| {
+                            !taken[*i]
+                                && old[*i].schedule == schedule
+                                && old[*i].lua_system.same_shape(&lua_system)
+                        });
+                        if let Some(i) = same {
+                            taken[i] = true;
+                        }
+                        plan.push((schedule, lua_system, same));
+                    }
+                }
+
+                // the old systems stay in the schedule either way, bevy can't take them out
+                let added = old
+                    .iter()
+                    .map(|system| (system.schedule, system.lua_system.ordering()))
+                    .chain(
+                        plan.iter()
+                            .filter(|(_, _, same)| same.is_none())
+                            .map(|(schedule, lua_system, _)| (*schedule, lua_system.ordering())),
+                    )
+                    .collect::<Vec<_>>();
+                let cycle = world
+                    .non_send_resource::<LuaScriptSystems>()
+                    .cycle_with(added);
+                if let Some(cycle) = cycle {
+                    error!(
+                        "not loading the systems of {id:?}, their ordering makes a cycle with \
+                        systems that are already scheduled: {cycle}"
+                    );
+                    world
+                        .non_send_resource_mut::<LuaScriptSystems>()
+                        .scripts
+                        .insert(id, old);
+                    continue;
+                }
+
+                let mut old = old.into_iter().map(Some).collect::<Vec<_>>();
+                let mut installed = vec![];
+                for (schedule, lua_system, same) in plan {
+                    match same {
+                        Some(i) => {
+                            let mut system = old[i].take().unwrap();
+                            system.reload(lua_system);
+                            installed.push(system);
+                        }
+                        None => installed.extend(install_lua_system(world, schedule, lua_system)),
+                    }
+                }
+                let mut script_systems = world.non_send_resource_mut::<LuaScriptSystems>();
+                old.into_iter()
+                    .flatten()
+                    .for_each(|system| script_systems.retire(system));
+                script_systems.scripts.insert(id, installed);
+            }
+            AssetEvent::Removed { id } => {
+                let mut script_systems = world.non_send_resource_mut::<LuaScriptSystems>();
+                let old = script_systems.scripts.remove(&id);
+                old.into_iter()
+                    .flatten()
+                    .for_each(|system| script_systems.retire(system));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The set names lua systems can be ordered against, the ones exposed from rust and the names
+/// and sets of the systems in every loaded script. Anything else is a set nothing is in.
+fn known_set_names(world: &World) -> HashSet<String> {
+    let mut names = world
+        .get_resource::<LuaSystemSets>()
+        .map(|sets| sets.keys().cloned().collect::<HashSet<_>>())
+        .unwrap_or_default();
+    for (_, lua_script) in world.resource::<Assets<LuaScript>>().iter() {
+        for lua_system in lua_script.systems.values().flatten() {
+            names.extend(lua_system.name.iter().cloned());
+            names.extend(lua_system.in_sets.iter().cloned());
+        }
+    }
+    names
+}
+
+/// Adds the system to its schedule, startup systems are run right away instead and give `None`.
+fn install_lua_system(
+    world: &mut World,
+    schedule: LuaSchedule,
+    lua_system: LuaSystem,
+) -> Option<InstalledLuaSystem> {
+    let LuaSystem {
+        lua_func,
+        system_parameters,
+        name,
+        after,
+        before,
+        in_sets,
+        run_if,
+    } = &lua_system;
+    let lua_func = Rc::new(RefCell::new(Some(lua_func.clone())));
+    let mut system = build_lua_system(world, lua_func.clone(), system_parameters.clone(), None);
+    let Some(label) = schedule.label() else {
+        // startup systems don't go into a schedule, they just run right away
+        if run_if
+            .clone()
+            .map_or(true, |run_if| check_run_condition(world, run_if))
+        {
+            system.initialize(world);
+            system.run((), world);
+        }
+        return None;
+    };
+
+    let enabled = Arc::new(AtomicBool::new(true));
+    let enabled2 = enabled.clone();
+    let mut system_config = system.run_if(move || enabled2.load(Ordering::Relaxed));
+    let mut predicate = None;
+    let mut predicate_func = None;
+    match run_if.clone() {
+        None => {}
+        Some(LuaRunCondition::Lua {
+            lua_func,
+            system_parameters,
+        }) => {
+            // lua predicates need the lua vm mutably so they can't be a bevy condition,
+            // instead they run as their own system right before this one
+            let passed = Arc::new(AtomicBool::new(false));
+            let slot = Rc::new(RefCell::new(Some(lua_func)));
+            let predicate_system =
+                build_lua_system(world, slot.clone(), system_parameters, Some(passed.clone()));
+            let enabled = enabled.clone();
+            predicate = Some(predicate_system.run_if(move || enabled.load(Ordering::Relaxed)));
+            predicate_func = Some(slot);
+            system_config = system_config.run_if(move || passed.load(Ordering::Relaxed));
+        }
+        Some(LuaRunCondition::EveryNFrames(n)) => {
+            system_config = system_config.run_if(every_n_frames(n));
+        }
+        Some(LuaRunCondition::OnTimer(seconds)) => {
+            system_config = system_config.run_if(on_timer(Duration::from_secs_f32(seconds)));
+        }
+        Some(LuaRunCondition::ResourceExists(component_id)) => {
+            system_config = system_config.run_if(resource_exists_by_id(component_id));
+        }
+        Some(LuaRunCondition::InState { resource, variant }) => {
+            system_config = system_config.run_if(in_state_by_name(resource, variant));
+        }
+    }
+
+    if let Some(name) = name {
+        system_config = system_config.in_set(LuaSystemSet(name.clone()));
+    }
+    for name in after {
+        system_config = system_config.after(LuaSystemSets::resolve(world, name));
+    }
+    for name in before {
+        system_config = system_config.before(LuaSystemSets::resolve(world, name));
+    }
+    for name in in_sets {
+        system_config = system_config.in_set(LuaSystemSets::resolve(world, name));
+    }
+    if let Some(predicate) = predicate {
+        system_config = (predicate, system_config).chain_ignore_deferred();
+    }
+    world
+        .resource_mut::<Schedules>()
+        .add_systems(label, system_config);
+    Some(InstalledLuaSystem {
+        schedule,
+        lua_system,
+        lua_func,
+        predicate: predicate_func,
+        enabled,
+    })
+}
+
+/// Evaluates a run condition a single time, for startup systems that never get scheduled.
+fn check_run_condition(world: &mut World, run_if: LuaRunCondition) -> bool {
+    match run_if {
+        LuaRunCondition::Lua {
+            lua_func,
+            system_parameters,
+        } => {
+            let passed = Arc::new(AtomicBool::new(false));
+            let mut predicate = build_lua_system(
+                world,
+                Rc::new(RefCell::new(Some(lua_func))),
+                system_parameters,
+                Some(passed.clone()),
+            );
+            predicate.initialize(world);
+            predicate.run((), world);
+            passed.load(Ordering::Relaxed)
+        }
+        LuaRunCondition::EveryNFrames(n) => run_condition_once(world, every_n_frames(n)),
+        LuaRunCondition::OnTimer(seconds) => {
+            run_condition_once(world, on_timer(Duration::from_secs_f32(seconds)))
+        }
+        LuaRunCondition::ResourceExists(component_id) => {
+            run_condition_once(world, resource_exists_by_id(component_id))
+        }
+        LuaRunCondition::InState { resource, variant } => {
+            run_condition_once(world, in_state_by_name(resource, variant))
+        }
+    }
+}
+
+fn run_condition_once<M>(world: &mut World, condition: impl IntoSystem<(), bool, M>) -> bool {
+    let mut condition = IntoSystem::into_system(condition);
+    condition.initialize(world);
+    condition.run((), world)
+}
+
+fn every_n_frames(n: u32) -> impl FnMut(Local<u32>) -> bool + Clone {
+    move |mut frames: Local<u32>| {
+        *frames += 1;
+        *frames % n == 0
+    }
+}
+
+fn resource_exists_by_id(component_id: ComponentId) -> impl Fn(&World) -> bool + Clone {
+    move |world: &World| world.get_resource_by_id(component_id).is_some()
+}
+
+/// Checks the current variant of a reflected `State<S>` resource by name.
+fn in_state_by_name(resource: ComponentType, variant: String) -> impl Fn(&World) -> bool + Clone {
+    move |world: &World| {
+        let (component_id, type_id) = resource.ids();
+        let Some(ptr) = world.get_resource_by_id(component_id) else {
+            return false;
+        };
+        let app_registry = world.resource::<AppTypeRegistry>().read();
+        let Some(reflect_from_ptr) = app_registry.get_type_data::<ReflectFromPtr>(type_id) else {
+            return false;
+        };
+        let state = unsafe { reflect_from_ptr.as_reflect(ptr) };
+        let ReflectRef::TupleStruct(state) = state.reflect_ref() else {
+            return false;
+        };
+        match state.field(0).map(|current| current.reflect_ref()) {
+            Some(ReflectRef::Enum(current)) => current.variant_name() == variant,
+            _ => false,
+        }
+    }
+}
+
+/// Turns a lua function and its [`SystemParameter`]s into a bevy system whose access is
+/// exactly what the parameters asked for, so it can be scheduled alongside rust systems.
+fn build_lua_system(
+    world: &mut World,
+    lua_func: LuaFunctionSlot,
+    system_parameters: Vec<SystemParameter>,
+    passed: Option<Arc<AtomicBool>>,
+) -> BoxedSystem {
+    world.init_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>();
+    let object_function_registry = world
+        .get_non_send_resource::<Rc<RefCell<ObjectFunctionRegistry>>>()
+        .unwrap()
+        .clone();
+
+    let mut queries = vec![];
+    let mut resources = vec![];
+    for system_parameter in &system_parameters {
+        match system_parameter {
+            SystemParameter::Query(lua_query) => {
+                let lua_query = lua_query.clone();
+                queries.push(QueryParamBuilder::new_box(
+                    move |builder: &mut QueryBuilder<FilteredEntityMut>| lua_query.build(builder),
+                ));
+            }
+            SystemParameter::Resource(resource_component_type) => {
+                resources.push(*resource_component_type);
+            }
+            SystemParameter::CommandQueue => {}
+        }
+    }
+    let resources =
+        FilteredResourcesMutParamBuilder::new(move |builder: &mut FilteredResourcesMutBuilder| {
+            for resource_component_type in resources {
+                match resource_component_type {
+                    ComponentType::Ref((component_id, _))
+                    | ComponentType::OptRef((component_id, _)) => {
+                        builder.add_read_by_id(component_id);
+                    }
+                    ComponentType::Mut((component_id, _))
+                    | ComponentType::OptMut((component_id, _)) => {
+                        builder.add_write_by_id(component_id);
+                    }
+                }
+            }
+        });
+
+    let state = SendWrapper::new(LuaSystemState {
+        lua_func,
+        system_parameters,
+        object_function_registry,
+        passed,
+    });
+    let system = (
+        ParamBuilder,
+        ParamBuilder,
+        queries,
+        resources,
+        ParamBuilder,
+        ParamBuilder,
+        ParamBuilder,
+    )
+        .build_state(world)
+        .build_any_system(
+            move |mut lua: NonSendMut<LuaVm>,
+                  app_registry: Res<AppTypeRegistry>,
+                  mut queries: Vec<Query<FilteredEntityMut>>,
+                  mut resources: FilteredResourcesMut,
+                  mut command_queue: Deferred<CommandQueueWrapper>,
+                  change_tick: SystemChangeTick,
+                  entities: &Entities| {
+                command_queue.set_entities(Some(entities));
+                run_lua_system(
+                    &state,
+                    &mut lua,
+                    &app_registry,
+                    &mut queries,
+                    &mut resources,
+                    &mut command_queue,
+                    change_tick,
+                );
+                command_queue.set_entities(None);
+            },
+        );
+    Box::new(system)
+}
+
+fn run_lua_system(
+    state: &LuaSystemState,
+    lua: &mut LuaVm,
+    app_registry: &AppTypeRegistry,
+    queries: &mut [Query<FilteredEntityMut>],
+    resources: &mut FilteredResourcesMut,
+    command_queue: &mut CommandQueueWrapper,
+    change_tick: SystemChangeTick,
+) {
+    let Some(stashed_function) = state.lua_func.borrow().clone() else {
+        // the script this came from was unloaded
+        if let Some(passed) = &state.passed {
+            passed.store(false, Ordering::Relaxed);
+        }
+        return;
+    };
+    let mut ptr_states = vec![];
+    let ofr1 = state.object_function_registry.clone();
+    let exec = lua
+        .try_enter(|ctx| {
+            let func = ctx.fetch(&stashed_function);
+            let mut things = vec![];
+            let mut queries = queries.iter_mut();
+
+            for system_parameter in &state.system_parameters {
+                let ptr_state = Rc::new(RefCell::new(PtrState::Valid));
+                let ptr_state2 = ptr_state.clone();
+                match system_parameter {
+                    SystemParameter::Query(lua_query) => {
+                        let rows = QueryRows::new(
+                            queries.next().unwrap(),
+                            lua_query.clone(),
+                            app_registry.clone(),
+                            ofr1.clone(),
+                            &change_tick,
+                            ptr_state.clone(),
+                        );
+                        ptr_states.push(ptr_state);
+                        things.push(rows.into_table(ctx).into_value(ctx));
+                    }
+                    SystemParameter::CommandQueue => {
+                        let reflect_mut = ReflectPtr::new_mut(
+                            &mut *command_queue,
+                            ptr_state2.clone(),
+                            ofr1.clone(),
+                        );
+                        things.push(reflect_mut.into_value(&ctx));
+                        ptr_states.push(ptr_state);
+                    }
+                    SystemParameter::Resource(resource_component_type) => {
+                        match resource_component_type {
+                            ComponentType::Ref((component_id, type_id))
+                            | ComponentType::OptRef((component_id, type_id)) => {
+                                let Some(x) = resources.get_by_id(*component_id) else {
+                                    things.push(Value::Nil);
+                                    continue;
+                                };
+                                let app_registry = app_registry.read();
+                                let reflect_data = app_registry.get(*type_id).unwrap();
+                                let reflect_from_ptr =
+                                    reflect_data.data::<ReflectFromPtr>().unwrap();
+                                let value = unsafe { reflect_from_ptr.as_reflect(x) };
+                                things.push(
+                                    ReflectPtr::new_ref(value, ptr_state2.clone(), ofr1.clone())
+                                        .into_value(&ctx),
+                                );
+                            }
+                            ComponentType::Mut((component_id, type_id))
+                            | ComponentType::OptMut((component_id, type_id)) => {
+                                let Some(x) = resources.get_mut_by_id(*component_id) else {
+                                    things.push(Value::Nil);
+                                    continue;
+                                };
+                                let app_registry = app_registry.read();
+                                let reflect_data = app_registry.get(*type_id).unwrap();
+                                let reflect_from_ptr =
+                                    reflect_data.data::<ReflectFromPtr>().unwrap();
+                                let value = unsafe {
+                                    ReflectPtr::new_tracked(
+                                        x,
+                                        reflect_from_ptr,
+                                        ptr_state2.clone(),
+                                        ofr1.clone(),
+                                    )
+                                };
+                                things.push(value.into_value(&ctx));
+                            }
+                        }
+                        ptr_states.push(ptr_state);
+                    }
+                }
+            }
+
+            Ok(ctx.stash(Executor::start(ctx, func, Variadic(things))))
+        })
+        .unwrap();
+    match &state.passed {
+        Some(passed) => match lua.execute::<bool>(&exec) {
+            Ok(result) => passed.store(result, Ordering::Relaxed),
+            Err(err) => {
+                passed.store(false, Ordering::Relaxed);
+                warn!("lua run condition errored, treating it as false: {err}");
+            }
+        },
+        None => {
+            if let Err(err) = lua.execute::<()>(&exec) {
+                error!("lua system errored: {err}");
+            }
+        }
+    }
+    for ptr_state in ptr_states.iter() {
+        *ptr_state.borrow_mut() = PtrState::Invalid;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ordering(name: &str, before: &str) -> LuaOrdering {
+        LuaOrdering {
+            name: Some(name.to_string()),
+            sets: vec![name.to_string()],
+            after: vec![],
+            before: vec![before.to_string()],
+        }
+    }
+
+    #[test]
+    fn retired_systems_still_order() {
+        let mut systems = LuaScriptSystems::default();
+        systems
+            .retired
+            .push((LuaSchedule::Update, ordering("a", "b")));
+
+        let cycle = systems.cycle_with([(LuaSchedule::Update, ordering("b", "a"))]);
+        assert!(cycle.is_some());
+        // other schedules don't order against it
+        let elsewhere = systems.cycle_with([(LuaSchedule::FixedUpdate, ordering("b", "a"))]);
+        assert!(elsewhere.is_none());
+        // and startup systems aren't ordered at all
+        let startup = systems.cycle_with([(LuaSchedule::Startup, ordering("c", "c"))]);
+        assert!(startup.is_none());
+    }
+}
@@ -19,7 +19,27 @@ where
         userdata.into()
     }
 
+    /// Built once per type and kept in the `__metatables` global, so `edit_metatable` can't
+    /// depend on `self`.
     fn metatable<'gc>(&self, ctx: &Context<'gc>) -> Table<'gc> {
+        let metatables = match ctx.globals().get::<_, Value>(*ctx, "__metatables").unwrap() {
+            Value::Table(metatables) => metatables,
+            _ => {
+                let metatables = Table::new(ctx);
+                ctx.set_global("__metatables", metatables);
+                metatables
+            }
+        };
+        let key = std::any::type_name::<Self>();
+        if let Value::Table(metatable) = metatables.get::<_, Value>(*ctx, key).unwrap() {
+            return metatable;
+        }
+        let metatable = self.build_metatable(ctx);
+        metatables.set(*ctx, key, metatable).unwrap();
+        metatable
+    }
+
+    fn build_metatable<'gc>(&self, ctx: &Context<'gc>) -> Table<'gc> {
         let mut metatable = Table::new(ctx);
 
         metatable
@@ -42,8 +62,8 @@ where
                 "__index",
                 Callback::from_fn(ctx, move |ctx, _fuel, mut stack| {
                     let (this, key): (&Self, Value) = stack.consume(ctx)?;
-                    let s = key.into_string(ctx).unwrap().to_str().unwrap();
-                    stack.push_front(this.lua_index(&ctx, s));
+                    let value = this.lua_index(&ctx, key)?;
+                    stack.push_front(value);
 
                     Ok(CallbackReturn::Return)
                 }),
@@ -56,8 +76,7 @@ where
                 "__newindex",
                 Callback::from_fn(ctx, move |ctx, _fuel, mut stack| {
                     let (this, key, new_value): (&Self, Value, Value) = stack.consume(ctx)?;
-                    let s = key.into_string(ctx).unwrap().to_str().unwrap();
-                    this.lua_new_index(&ctx, s, new_value);
+                    this.lua_new_index(&ctx, key, new_value)?;
 
                     Ok(CallbackReturn::Return)
                 }),
@@ -73,9 +92,18 @@ where
 
     fn lua_to_string(&self) -> String;
 
-    fn lua_index<'gc>(&self, ctx: &Context<'gc>, key: &str) -> Value<'gc>;
-
-    fn lua_new_index<'gc>(&self, ctx: &Context<'gc>, key: &str, new_value: Value<'gc>);
+    fn lua_index<'gc>(
+        &self,
+        ctx: &Context<'gc>,
+        key: Value<'gc>,
+    ) -> Result<Value<'gc>, piccolo::Error<'gc>>;
+
+    fn lua_new_index<'gc>(
+        &self,
+        ctx: &Context<'gc>,
+        key: Value<'gc>,
+        new_value: Value<'gc>,
+    ) -> Result<(), piccolo::Error<'gc>>;
 
     fn from_value_2<'gc>(_ctx: Context<'gc>, value: Value<'gc>) -> Result<&'gc Self, TypeError> {
         value.as_static_user_data::<Self>()